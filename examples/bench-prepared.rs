@@ -0,0 +1,91 @@
+/// Measures repeated broadcasts of the same message. A prepared message is broadcast without
+/// copying it, while broadcasting a plain message requires a fresh copy of the data for every
+/// broadcast. Compare the two with
+///
+/// ```sh
+/// cargo run --release --example bench-prepared -- prepared
+/// cargo run --release --example bench-prepared -- copied
+/// ```
+///
+/// An optional second argument sets the number of broadcasts (default 10000).
+extern crate ws;
+
+use std::env;
+use std::io::Read;
+use std::net::TcpStream;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Instant;
+
+use ws::{Builder, Message, Settings, Sender};
+
+const SIZE: usize = 64 << 10;
+const CONNECTIONS: usize = 8;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let prepared = match args.next().as_ref().map(|mode| &mode[..]) {
+        Some("prepared") | None => true,
+        Some("copied") => false,
+        Some(mode) => panic!("Unknown mode {}, expected prepared or copied.", mode),
+    };
+    let broadcasts: usize = args.next().map(|n| n.parse().unwrap()).unwrap_or(10_000);
+
+    let (tx, rx) = channel();
+    let ws = Builder::new().with_settings(Settings {
+        queue_size: broadcasts,
+        ..Settings::default()
+    }).build(move |out: Sender| {
+        tx.send(out).unwrap();
+        |_| Ok(())
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    // Every client reads until it has received every broadcast
+    let clients: Vec<_> = (0..CONNECTIONS).map(|_| {
+        let mut client = TcpStream::connect(addr).unwrap();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; SIZE];
+            let mut left = SIZE * broadcasts;
+            while left > 0 {
+                left -= client.read(&mut buf).unwrap();
+            }
+        })
+    }).collect();
+    for _ in 0..CONNECTIONS {
+        rx.recv().unwrap();
+    }
+
+    let snapshot = vec![1u8; SIZE];
+    let start = Instant::now();
+    if prepared {
+        let msg = Message::binary(snapshot).prepare();
+        for _ in 0..broadcasts {
+            handle.broadcast_prepared(&msg).unwrap();
+        }
+    } else {
+        for _ in 0..broadcasts {
+            handle.broadcast(snapshot.clone()).unwrap();
+        }
+    }
+    for client in clients {
+        client.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    println!("{} broadcasts of {} bytes {} to {} connections took {} ms.",
+             broadcasts,
+             SIZE,
+             if prepared { "prepared" } else { "copied" },
+             CONNECTIONS,
+             elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000);
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
@@ -0,0 +1,88 @@
+/// Measures the memory used to buffer a large broadcast to many connections. Broadcasts are
+/// buffered from a single shared copy of the data, while sending the same data to each
+/// connection individually buffers one copy per connection, which is what a broadcast used to
+/// cost. Compare the two with
+///
+/// ```sh
+/// cargo run --release --example broadcast-memory -- shared
+/// cargo run --release --example broadcast-memory -- copied
+/// ```
+///
+/// An optional second argument sets the number of connections (default 10000). Raise the open
+/// file limit first, for example with `ulimit -n 30000`. Memory is read from /proc, so this only
+/// works on Linux.
+extern crate ws;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::net::TcpStream;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, Settings, Sender};
+
+const SIZE: usize = 1 << 20;
+
+// Resident set size of this process in kB
+fn rss() -> usize {
+    let mut status = String::new();
+    File::open("/proc/self/status").unwrap().read_to_string(&mut status).unwrap();
+    status.lines()
+        .find(|line| line.starts_with("VmRSS:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse().ok())
+        .unwrap()
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let shared = match args.next().as_ref().map(|mode| &mode[..]) {
+        Some("shared") | None => true,
+        Some("copied") => false,
+        Some(mode) => panic!("Unknown mode {}, expected shared or copied.", mode),
+    };
+    let connections: usize = args.next().map(|n| n.parse().unwrap()).unwrap_or(10_000);
+
+    let (tx, rx) = channel();
+    let ws = Builder::new().with_settings(Settings {
+        max_connections: connections,
+        ..Settings::default()
+    }).build(move |out: Sender| {
+        tx.send(out).unwrap();
+        |_| Ok(())
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    // The clients never read, so everything stays buffered on the server
+    let clients: Vec<_> = (0..connections).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    let senders: Vec<_> = (0..connections).map(|_| rx.recv().unwrap()).collect();
+
+    let before = rss();
+    if shared {
+        handle.broadcast(vec![1u8; SIZE]).unwrap();
+    } else {
+        for sender in senders.iter() {
+            sender.send(vec![1u8; SIZE]).unwrap();
+        }
+    }
+    // Give the event loop time to buffer the data on every connection
+    thread::sleep(Duration::from_secs(2));
+    let after = rss();
+
+    println!("Buffering {} bytes {} on {} connections used {} kB.",
+             SIZE,
+             if shared { "shared" } else { "copied" },
+             clients.len(),
+             after.saturating_sub(before));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
@@ -0,0 +1,30 @@
+/// Embedding a WebSocket in a larger application. The event loop runs on its own thread, while
+/// the rest of the application keeps the returned Sender to open connections and to shut the
+/// WebSocket down when it is done.
+extern crate ws;
+extern crate env_logger;
+
+use std::thread::sleep;
+use std::time::Duration;
+
+use ws::Builder;
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let (sender, handle) = Builder::new().build_threaded(|out: ws::Sender| {
+        move |msg| {
+            println!("Got message '{}'. ", msg);
+            out.send(msg)
+        }
+    }).unwrap();
+
+    // Try it with `nc -l 127.0.0.1 3012`
+    sender.connect("127.0.0.1:3012".into()).unwrap();
+
+    // The application carries on with its own work here
+    sleep(Duration::from_secs(10));
+
+    sender.shutdown().unwrap();
+    handle.join().unwrap().unwrap();
+}
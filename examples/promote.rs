@@ -0,0 +1,110 @@
+/// Promoting a connection's handler at runtime. Handlers aren't `Send`, so they can't be swapped
+/// through the event loop's channel. Instead the connection is given a single enum handler that
+/// dispatches to whichever stage the connection is in and replaces that stage in place, here
+/// promoting an unauthenticated session to an authenticated one after a successful login.
+///
+/// Try it with `nc 127.0.0.1 3012` and send `login alice open-sesame`.
+extern crate ws;
+extern crate env_logger;
+
+use std::mem::replace;
+
+use ws::{listen, Handler, Sender, Message, Result};
+
+const PASSWORD: &'static str = "open-sesame";
+
+// Only understands the login command
+struct Unauthenticated {
+    out: Sender,
+}
+
+impl Handler for Unauthenticated {
+    fn on_open(&mut self) -> Result<()> {
+        self.out.send("Please log in.\n")
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.out.send("Usage: login <user> <password>\n")
+    }
+}
+
+// Echoes everything back to the logged in user
+struct Authenticated {
+    out: Sender,
+    user: String,
+}
+
+impl Handler for Authenticated {
+    fn on_open(&mut self) -> Result<()> {
+        self.out.send(format!("Welcome, {}.\n", self.user))
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(format!("{}: {}", self.user, msg))
+    }
+}
+
+enum Session {
+    Unauthenticated(Unauthenticated),
+    Authenticated(Authenticated),
+    // Only observable while a transition is in progress
+    Promoting,
+}
+
+impl Session {
+    // Replace the current stage with an authenticated one, carrying over the Sender.
+    fn promote(&mut self, user: String) -> Result<()> {
+        if let Session::Unauthenticated(stage) = replace(self, Session::Promoting) {
+            *self = Session::Authenticated(Authenticated {
+                out: stage.out,
+                user: user,
+            });
+        }
+        // The new stage gets its own on_open so it can set itself up
+        self.on_open()
+    }
+}
+
+fn credentials(msg: &Message) -> Option<String> {
+    let text = match msg.as_text() {
+        Ok(text) => text.trim(),
+        Err(_) => return None,
+    };
+    let mut parts = text.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("login"), Some(user), Some(password)) if password == PASSWORD => Some(user.to_owned()),
+        _ => None,
+    }
+}
+
+impl Handler for Session {
+    fn on_open(&mut self) -> Result<()> {
+        match *self {
+            Session::Unauthenticated(ref mut stage) => stage.on_open(),
+            Session::Authenticated(ref mut stage) => stage.on_open(),
+            Session::Promoting => Ok(()),
+        }
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let user = match *self {
+            Session::Unauthenticated(ref mut stage) => {
+                match credentials(&msg) {
+                    Some(user) => user,
+                    None => return stage.on_message(msg),
+                }
+            }
+            Session::Authenticated(ref mut stage) => return stage.on_message(msg),
+            Session::Promoting => return Ok(()),
+        };
+        self.promote(user)
+    }
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    listen("127.0.0.1:3012", |out| {
+        Session::Unauthenticated(Unauthenticated { out: out })
+    }).unwrap()
+}
@@ -1,33 +1,74 @@
 use std::convert::Into;
 use std::borrow::Cow;
+use std::time::{Duration, Instant};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use url;
 use mio;
 use mio::Token;
 
 use message;
-use result::{Result, Error};
+use result::{Result, Error, Kind};
 use protocol::CloseCode;
 use io::ALL;
 
 use std::net::{SocketAddr, ToSocketAddrs};
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum Signal
 {
     Message(message::Message),
+    Messages(Vec<message::Message>),
+    Shared(Arc<Vec<u8>>),
+    MessageWithDeadline(message::Message, Instant),
+    MessageWithNotify(message::Message, Token),
+    MessageIfOpen(message::Message, mpsc::Sender<bool>),
     Close(CloseCode, Cow<'static, str>),
-    Connect(String),
+    Abort,
+    Connect(String, OutgoingSlot),
+    ConnectAddr(SocketAddr, OutgoingSlot),
     Shutdown,
+    StopAccepting,
+    ResumeAccepting,
     Timeout {
         delay: u64,
         token: Token,
     },
     Cancel(mio::timer::Timeout),
+    CancelAll,
+    ReadTimeout(Duration),
+    WriteTimeout(Duration),
+    Diagnostics(mpsc::Sender<Diagnostics>),
+    QueuedBytes(mpsc::Sender<Result<usize>>),
+    LogLabel(String),
+    LabeledMessage(String, message::Message, mpsc::Sender<Result<usize>>),
 }
 
-#[derive(Debug, Clone)]
+/// A snapshot of the internal state of the event loop, see `Sender::diagnostics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Diagnostics {
+    /// The number of connections of the WebSocket.
+    pub connections: usize,
+    /// How many of the connections are still being established.
+    pub connecting: usize,
+    /// How many of the connections are open.
+    pub open: usize,
+    /// How many of the connections are closing.
+    pub closing: usize,
+    /// The number of bytes buffered for sending over all connections.
+    pub queued_bytes: usize,
+    /// The number of timeouts that are scheduled and have not fired or been cancelled yet.
+    pub pending_timeouts: usize,
+    /// How many times the event loop has woken up since it started running, including the
+    /// wakeup that took this snapshot.
+    pub iterations: u64,
+    /// How long the event loop spent handling the events of its previous wakeup.
+    pub last_poll_duration: Duration,
+}
+
+#[derive(Debug)]
 pub struct Command
 {
     token: Token,
@@ -53,7 +94,57 @@ impl Command
     }
 }
 
+// The number of outgoing connections that are queued or established, shared between the event
+// loop and all of its Senders so that `connect` can refuse once the limit is reached.
+#[derive(Debug)]
+pub struct OutgoingLimit {
+    count: AtomicUsize,
+    max: usize,
+}
+
+impl OutgoingLimit {
+    pub fn new(max: usize) -> Arc<OutgoingLimit> {
+        Arc::new(OutgoingLimit {
+            count: AtomicUsize::new(0),
+            max: max,
+        })
+    }
+    
+    fn acquire(limit: &Arc<OutgoingLimit>) -> Result<OutgoingSlot> {
+        let mut count = limit.count.load(Ordering::SeqCst);
+        loop {
+            if count >= limit.max {
+                return Err(Error::new(
+                    Kind::Capacity,
+                    format!("Unable to queue another outgoing connection, {} are already in use.", limit.max)))
+            }
+            match limit.count.compare_exchange(count, count + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Ok(OutgoingSlot(limit.clone())),
+                Err(actual) => count = actual,
+            }
+        }
+    }
+}
+
+// An outgoing connection counted against its limit until the slot is dropped, along with the
+// connect command or the connection that holds it.
+#[derive(Debug)]
+pub struct OutgoingSlot(Arc<OutgoingLimit>);
 
+impl Drop for OutgoingSlot {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+/// A handle for sending commands to the event loop on behalf of a connection.
+///
+/// Every Sender of a WebSocket feeds the same queue, and the event loop carries out the commands
+/// one at a time in the order they were queued. A broadcast is buffered on every connection,
+/// including the sender's own, before the next command is looked at, so messages queued from
+/// one thread are written to each connection in the order they were sent, whether they were
+/// broadcast or sent directly.
 #[derive(Clone)]
 pub struct Sender
 {
@@ -61,6 +152,8 @@ pub struct Sender
     channel: mio::channel::SyncSender<Command>,
     //接收方实现了mio的Evented trait 可以用来监听用epoll
     connection_id: u32,
+    client: bool,
+    outgoing: Arc<OutgoingLimit>,
 }
 
 impl Sender {
@@ -68,16 +161,51 @@ impl Sender {
         Sender {
             token: token,
             channel: channel,
-            connection_id: connection_id
+            connection_id: connection_id,
+            client: false,
+            outgoing: OutgoingLimit::new(usize::MAX),
         }
     }
     
+    /// Count the outgoing connections queued by this Sender against `limit`.
+    #[doc(hidden)]
+    pub fn outgoing(mut self, limit: Arc<OutgoingLimit>) -> Sender {
+        self.outgoing = limit;
+        self
+    }
+    
+    /// Mark this Sender as belonging to an outgoing connection.
+    #[doc(hidden)]
+    pub fn client(mut self) -> Sender {
+        self.client = true;
+        self
+    }
+    
     
     pub fn token(&self) -> Token {
         self.token
     }
     
+    /// Whether the connection of this Sender was made by `connect`, rather than accepted by the
+    /// WebSocket. A broadcaster is never a client.
+    pub fn is_client(&self) -> bool {
+        self.client
+    }
+    
+    /// The opposite of `is_client`.
+    pub fn is_server(&self) -> bool {
+        !self.client
+    }
+    
+    /// Whether this Sender is the broadcaster of a WebSocket, see `WebSocket::broadcaster`,
+    /// rather than the Sender of a single connection.
+    pub fn is_broadcaster(&self) -> bool {
+        self.token == ALL
+    }
     
+    /// Send a message over the connection.
+    ///
+    /// On the broadcaster this sends the message to every connection, just like `broadcast`.
     pub fn send<M>(&self, msg: M) -> Result<()>
                    where M: Into<message::Message>
     {
@@ -89,6 +217,87 @@ impl Sender {
     }
     
     
+    /// Send a prepared message over the connection. Only a reference to the prepared data is
+    /// queued and buffered, so sending the same prepared message over and over doesn't copy it.
+    pub fn send_prepared(&self, msg: &message::PreparedMessage) -> Result<()> {
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::Shared(msg.shared()),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
+    
+    
+    /// Send several messages over the connection as a single batch. The messages are buffered
+    /// together and in order, so nothing else sent on the connection can be interleaved
+    /// between them, and the whole batch only costs one trip through the event loop's queue.
+    pub fn send_all<M>(&self, msgs: Vec<M>) -> Result<()>
+                       where M: Into<message::Message>
+    {
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::Messages(msgs.into_iter().map(Into::into).collect()),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
+    
+    
+    /// Send a message over the connection, dropping it if it has not begun transmission by
+    /// `deadline`. This is useful for data that is worthless once it is stale. Dropped messages
+    /// are passed to the handler's `on_message_expired` method. The deadline is checked whenever
+    /// the connection is about to write, so a message stuck behind a stalled write is dropped
+    /// once the peer starts reading again rather than being delivered late.
+    pub fn send_with_deadline<M>(&self, msg: M, deadline: Instant) -> Result<()>
+                                 where M: Into<message::Message>
+    {
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::MessageWithDeadline(msg.into(), deadline),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
+    
+    
+    /// Send a message over the connection and have the handler's `on_sent` called with `token`
+    /// once the message has been completely written to the socket. No notification is given if
+    /// the connection closes before then.
+    pub fn send_and_notify<M>(&self, msg: M, token: Token) -> Result<()>
+                              where M: Into<message::Message>
+    {
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::MessageWithNotify(msg.into(), token),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
+    
+    
+    /// Send a message over the connection if it is still open, and find out whether it was.
+    /// `send` quietly drops messages once the connection is closing, whereas this reports
+    /// `true` to the returned receiver if the message was buffered, and `false` if it was
+    /// skipped because the connection is closing or already gone. The answer is always sent, so
+    /// the receiver is only hung up on if the event loop stops before getting to the message.
+    ///
+    /// The answer comes back through a receiver rather than as `Result<bool>` because a Sender
+    /// has no synchronous path to the event loop, it can only queue a command on the channel and
+    /// the connection state is only known once the loop handles it. Like with `diagnostics`,
+    /// don't block on the receiver from within a handler.
+    ///
+    /// On the broadcaster this sends the message to every open connection and reports whether
+    /// any of them buffered it.
+    pub fn try_send_if_open<M>(&self, msg: M) -> Result<mpsc::Receiver<bool>>
+                               where M: Into<message::Message>
+    {
+        let (tx, rx) = mpsc::channel();
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::MessageIfOpen(msg.into(), tx),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)?;
+        Ok(rx)
+    }
+    
+    /// Send a message to every open connection, including this one.
     pub fn broadcast<M>(&self, msg: M) -> Result<()>
                         where M: Into<message::Message>
     {
@@ -99,7 +308,19 @@ impl Sender {
         }).map_err(Error::from)
     }
     
+    /// Send a prepared message to every open connection, including this one. Like `send_prepared`,
+    /// this doesn't copy the prepared data.
+    pub fn broadcast_prepared(&self, msg: &message::PreparedMessage) -> Result<()> {
+        self.channel.send(Command {
+            token: ALL,
+            signal: Signal::Shared(msg.shared()),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
+    
     /// Send a close code to the other endpoint.
+    /// Output that is already buffered is still written, after which the connection is closed
+    /// and the handler's `on_close` is called with this code.
     #[inline]
     pub fn close(&self, code: CloseCode) -> Result<()> {
         self.channel.send(Command {
@@ -121,17 +342,57 @@ impl Sender {
         }).map_err(Error::from)
     }
     
+    /// Drop the connection immediately, without a closing handshake and without sending any
+    /// output that is still buffered. Where the platform allows it the socket is reset rather
+    /// than shut down, so a misbehaving peer doesn't cost any more bytes. The handler's
+    /// `on_close` is called with `CloseCode::Abnormal` and the reason "aborted". Use `close`
+    /// to end a connection gracefully instead.
+    ///
+    /// Calling `abort` on the broadcaster aborts every connection.
+    #[inline]
+    pub fn abort(&self) -> Result<()> {
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::Abort,
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
+    
     /// Queue a new connection on this WebSocket to the specified URL.
+    ///
+    /// The URL is resolved with a blocking DNS lookup on the event loop thread, which stalls
+    /// every other connection until the lookup returns. Use `connect_addr` with an address that
+    /// was resolved elsewhere to avoid this.
+    ///
+    /// A Capacity error is returned if `max_outgoing_connections` outgoing connections are
+    /// already queued or established.
     #[inline]
     pub fn connect(&self, url: String) -> Result<()> {
+        let slot = OutgoingLimit::acquire(&self.outgoing)?;
         self.channel.send(Command {
             token: self.token,
-            signal: Signal::Connect(url),
+            signal: Signal::Connect(url, slot),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
+    
+    /// Queue a new connection on this WebSocket to the given address. No DNS lookup is done.
+    /// Like `connect`, this counts against `max_outgoing_connections`.
+    #[inline]
+    pub fn connect_addr(&self, addr: SocketAddr) -> Result<()> {
+        let slot = OutgoingLimit::acquire(&self.outgoing)?;
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::ConnectAddr(addr, slot),
             connection_id: self.connection_id,
         }).map_err(Error::from)
     }
     
     /// Request that all connections terminate and that the WebSocket stop running.
+    ///
+    /// The connections are shut down one after another in ascending order of their tokens, see
+    /// `token`, and each handler's `on_shutdown` is called before the connection is closed. The
+    /// factory's `on_shutdown` is called once every connection has been shut down.
     #[inline]
     pub fn shutdown(&self) -> Result<()> {
         self.channel.send(Command {
@@ -141,6 +402,31 @@ impl Sender {
         }).map_err(Error::from)
     }
     
+    /// Stop accepting new connections, while the connections that are already open carry on
+    /// as usual. This is useful for draining a server before a restart. New connections are
+    /// left waiting in the listen backlog of the kernel until `resume_accepting` is called, so
+    /// the load balancer should stop routing to the server at the same time.
+    ///
+    /// Like `shutdown`, this applies to the whole WebSocket whichever Sender it is called on.
+    #[inline]
+    pub fn stop_accepting(&self) -> Result<()> {
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::StopAccepting,
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
+    
+    /// Accept new connections again after `stop_accepting`.
+    #[inline]
+    pub fn resume_accepting(&self) -> Result<()> {
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::ResumeAccepting,
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
+    
     /// Schedule a `token` to be sent to the WebSocket Handler's `on_timeout` method
     /// after `ms` milliseconds
     #[inline]
@@ -155,6 +441,118 @@ impl Sender {
         }).map_err(Error::from)
     }
     
+    /// Request a snapshot of the internal state of the event loop, which is useful for finding
+    /// out why a WebSocket appears to hang. The snapshot is taken once the event loop gets to
+    /// this request, so everything queued before it has been carried out by then, and it is sent
+    /// to the returned receiver.
+    ///
+    /// Don't block on the receiver from within a handler, the event loop can't take the
+    /// snapshot while the handler is waiting for it.
+    #[inline]
+    pub fn diagnostics(&self) -> Result<mpsc::Receiver<Diagnostics>> {
+        let (tx, rx) = mpsc::channel();
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::Diagnostics(tx),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)?;
+        Ok(rx)
+    }
+    
+    /// Request the number of bytes that are buffered for the connection and not yet written to
+    /// the socket, which a producer can use to decide whether to send or drop data. The count is
+    /// taken once the event loop gets to this request and sent to the returned receiver, so it
+    /// includes everything queued before it, but more may have been written or queued by the
+    /// time it is received. If the connection is gone by then, a NotFound error is sent instead.
+    ///
+    /// The count comes back through a receiver because a Sender has no synchronous path to the
+    /// event loop, it can only queue a command on the channel. Like with `diagnostics`, don't
+    /// block on the receiver from within a handler, the loop can't answer until the handler
+    /// returns.
+    ///
+    /// There is no message count to go with this. Messages sent to a connection are appended to
+    /// a single byte buffer, only broadcast data is queued separately as shared chunks, so the
+    /// number of messages still pending isn't known.
+    ///
+    /// On the broadcaster this counts the bytes buffered for all connections together.
+    #[inline]
+    pub fn queued_bytes(&self) -> Result<mpsc::Receiver<Result<usize>>> {
+        let (tx, rx) = mpsc::channel();
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::QueuedBytes(tx),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)?;
+        Ok(rx)
+    }
+    
+    /// Tag the connection with a label, such as a request or user id, that is included in the
+    /// library's log lines and error messages for the connection from then on, next to the
+    /// address of the other endpoint. Setting another label replaces the previous one.
+    ///
+    /// On the broadcaster this has no effect.
+    #[inline]
+    pub fn set_log_label(&self, label: String) -> Result<()> {
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::LogLabel(label),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
+    
+    /// Send a message to the connection that was tagged with `label` through `set_log_label`,
+    /// which lets an application address connections by its own ids rather than by token. This
+    /// works from any Sender, including the broadcaster.
+    ///
+    /// Once the event loop gets to this request, the number of connections the message was sent
+    /// to is reported to the returned receiver, or a NotFound error if no connection has the
+    /// label. If several connections share the label, `Settings::duplicate_labels` decides
+    /// whether the message goes to all of them or to none, with an Internal error reported
+    /// instead. Like with `diagnostics`, don't block on the receiver from within a handler.
+    pub fn send_to_label<M>(&self, label: &str, msg: M) -> Result<mpsc::Receiver<Result<usize>>>
+                            where M: Into<message::Message>
+    {
+        let (tx, rx) = mpsc::channel();
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::LabeledMessage(label.into(), msg.into(), tx),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)?;
+        Ok(rx)
+    }
+    
+    /// Require the other endpoint to send some data within `timeout`. If nothing has been read
+    /// from the connection by then, the connection is closed with `CloseCode::Away`. Unlike a
+    /// timeout scheduled with `timeout`, this applies to the read that is pending now, for
+    /// example the reply to a request that was just sent, and has no effect once data arrives.
+    /// The deadline starts once the event loop gets to this request, so data that is read
+    /// before then doesn't count. Every call sets a deadline of its own.
+    ///
+    /// On the broadcaster this sets a deadline for every connection.
+    #[inline]
+    pub fn set_read_timeout(&self, timeout: Duration) -> Result<()> {
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::ReadTimeout(timeout),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
+    
+    /// Require buffered output to be written within `timeout`. If output is still waiting to be
+    /// written by then, the other endpoint isn't reading, and the connection is closed with
+    /// `CloseCode::Away` and dropped without flushing the rest. Every call sets a deadline of
+    /// its own.
+    ///
+    /// On the broadcaster this sets a deadline for every connection.
+    #[inline]
+    pub fn set_write_timeout(&self, timeout: Duration) -> Result<()> {
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::WriteTimeout(timeout),
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
+    
     /// Queue the cancellation of a previously scheduled timeout.
     ///
     /// This method is not guaranteed to prevent the timeout from occuring, because it is
@@ -168,5 +566,21 @@ impl Sender {
             connection_id: self.connection_id,
         }).map_err(Error::from)
     }
+    
+    /// Queue the cancellation of every timeout this Sender's connection still has scheduled,
+    /// for instance when a connection with outstanding request deadlines closes early. Timeouts
+    /// that are scheduled after this call are not affected. Like `cancel`, this cannot stop a
+    /// timeout that has already fired but not yet been handled.
+    ///
+    /// On the broadcaster this cancels the timeouts that were scheduled through the
+    /// broadcaster.
+    #[inline]
+    pub fn cancel_all_timeouts(&self) -> Result<()> {
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::CancelAll,
+            connection_id: self.connection_id,
+        }).map_err(Error::from)
+    }
 }
 
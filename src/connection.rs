@@ -1,9 +1,13 @@
 use std::mem::replace;
+use std::cmp;
 use std::borrow::Borrow;
 use std::io::{Write, Read, Cursor, Seek, SeekFrom};
 use std::net::SocketAddr;
 use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::str::from_utf8;
+use std::time::{Duration, Instant};
 
 use url;
 use mio::{Token, Ready};
@@ -12,9 +16,10 @@ use mio::tcp::TcpStream;
 
 use message::Message;
 use protocol::{CloseCode, OpCode};
-use result::{Result, Error, Kind};
+use result::{Result, Error, Kind, close_code_for};
 use handler::Handler;
 use stream::{Stream, TryReadBuf, TryWriteBuf};
+use communication::OutgoingSlot;
 
 use self::State::*;
 use self::Endpoint::*;
@@ -32,6 +37,51 @@ pub enum State {
     FinishedClose,
 }
 
+/// An operation that was given a deadline with `Sender::set_read_timeout` or
+/// `Sender::set_write_timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deadline {
+    /// Some data has to be read, the number is how many reads had returned data when the
+    /// deadline was set.
+    Read(usize),
+    /// The output has to be written.
+    Write,
+}
+
+/// Tracks the bytes allocated for the buffers of all connections of a WebSocket, so that buffers
+/// only grow while `Settings::global_buffer_budget` allows it.
+#[derive(Debug, Clone)]
+pub struct BufferBudget {
+    used: Arc<AtomicUsize>,
+    limit: Option<usize>,
+}
+
+impl BufferBudget {
+    pub fn new(limit: Option<usize>) -> BufferBudget {
+        BufferBudget {
+            used: Arc::new(AtomicUsize::new(0)),
+            limit: limit,
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    fn allows(&self, additional: usize) -> bool {
+        match self.limit {
+            Some(limit) => self.used() + additional <= limit,
+            None => true,
+        }
+    }
+
+    // Account for an allocation that changed from `old` to `new` bytes.
+    fn update(&self, old: usize, new: usize) {
+        // only ever touched from the event loop thread
+        self.used.store(self.used() + new - old, Ordering::Relaxed)
+    }
+}
+
 /// A little more semantic than a boolean
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Endpoint {
@@ -50,7 +100,6 @@ impl State {
         }
     }
 
-    #[allow(dead_code)]
     #[inline]
     pub fn is_open(&self) -> bool {
         match *self {
@@ -59,6 +108,14 @@ impl State {
         }
     }
 
+    #[inline]
+    pub fn is_awaiting_close(&self) -> bool {
+        match *self {
+            State::AwaitingClose => true,
+            _ => false,
+        }
+    }
+
     #[inline]
     pub fn is_closing(&self) -> bool {
         match *self {
@@ -69,6 +126,14 @@ impl State {
     }
 }
 
+// Data buffered on many connections at once, such as a broadcast, goes out from a single shared
+// copy. It is written once out_buffer has been sent up to `at`.
+struct SharedChunk {
+    at: usize,
+    data: Arc<Vec<u8>>,
+    pos: usize,
+}
+
 pub struct Connection<H>
     where H: Handler
 {
@@ -89,14 +154,39 @@ pub struct Connection<H>
     settings: Settings,
     //连接id,可能会出现同一个socket，不同id的情况。
     connection_id: u32,
+    // Byte ranges of out_buffer holding messages that are dropped if they have not begun
+    // transmission by their deadline, in buffer order.
+    deadlines: VecDeque<(usize, usize, Instant)>,
+    // The peer has shut down its write side, so the connection only lives on to flush the
+    // out_buffer.
+    peer_closed: bool,
+    // Shared data interleaved with out_buffer, in buffer order.
+    shared: VecDeque<SharedChunk>,
+    // Tokens to pass to on_sent once out_buffer has been written up to the given end offset, in
+    // buffer order.
+    notify: VecDeque<(usize, Token)>,
+    // The close code and reason of a close started by this endpoint, reported to on_close once
+    // the close completes.
+    local_close: Option<(CloseCode, String)>,
+    // The number of reads that returned data, to tell whether a read deadline was met.
+    reads: usize,
+    budget: BufferBudget,
+    // The bytes of in_buffer and out_buffer that are accounted for in the budget.
+    allocated: usize,
+    // An application defined label that is logged along with the peer address.
+    log_label: Option<String>,
+    // Counts an outgoing connection against max_outgoing_connections for as long as it lives.
+    outgoing: Option<OutgoingSlot>,
 
 }
 
 impl<H> Connection<H>
     where H: Handler
 {
-    pub fn new(tok: Token, sock: TcpStream, handler: H, settings: Settings, connection_id: u32) -> Connection<H> {
-        Connection {
+    pub fn new(tok: Token, sock: TcpStream, handler: H, settings: Settings, connection_id: u32,
+               budget: BufferBudget) -> Connection<H>
+    {
+        let mut conn = Connection {
             token: tok,
             socket: Stream::tcp(sock),
             state: Connecting(
@@ -110,15 +200,36 @@ impl<H> Connection<H>
             handler: handler,
             addresses: Vec::new(),
             settings: settings,
-            connection_id: connection_id
-        }
+            connection_id: connection_id,
+            deadlines: VecDeque::new(),
+            peer_closed: false,
+            shared: VecDeque::new(),
+            notify: VecDeque::new(),
+            local_close: None,
+            reads: 0,
+            budget: budget,
+            allocated: 0,
+            log_label: None,
+            outgoing: None,
+        };
+        conn.account_buffers();
+        conn
     }
 
     pub fn open(&mut self) -> Result<()> {
         trace!("accept socket{:?}", self.token);
-        if let Connecting(ref req, ref res) = replace(&mut self.state, Open) {
+        if let Connecting(..) = replace(&mut self.state, Open) {
             trace!("accept new socket change state connecting  to open {}", self.peer_addr());
-            return Ok(());
+            if let Err(err) = self.handler.on_open() {
+                // The handler vetoed the connection, so close it whatever kind of error this is
+                debug!("Handler rejected connection to {}: {}", self.peer_addr(), err);
+                let reason = format!("{}", err);
+                self.handler.on_error(err);
+                self.send_close(CloseCode::Policy, reason)?;
+                self.disconnect();
+                return Ok(())
+            }
+            Ok(self.check_events())
         } else {
             Err(Error::new(Kind::Internal, "Tried to write socket while not in connecting state!"))
         }
@@ -132,9 +243,10 @@ impl<H> Connection<H>
     pub fn as_client(&mut self, url: String, addrs: Vec<SocketAddr>) -> Result<()> {
         trace!("new client socket half ");
         match self.state {
-            State::Open => {
+            State::Connecting(..) => {
                 self.addresses = addrs;
-                self.events.insert(Ready::readable());//监听可读
+                // the connect is complete once the socket turns writable, see `write`
+                self.events.insert(Ready::writable());
                 self.endpoint = Endpoint::Client(url);
                 Ok(())
             }
@@ -159,14 +271,31 @@ impl<H> Connection<H>
         self.connection_id
     }
 
+    // Identifies the connection in log lines and error messages.
     fn peer_addr(&self) -> String {
-        if let Ok(addr) = self.socket.peer_addr() {
+        let addr = if let Ok(addr) = self.socket.peer_addr() {
             addr.to_string()
         } else {
             "UNKNOWN".into()
+        };
+        match self.log_label {
+            Some(ref label) => format!("{} [{}]", addr, label),
+            None => addr,
         }
     }
 
+    pub fn log_label(&self) -> Option<&str> {
+        self.log_label.as_ref().map(|label| &label[..])
+    }
+
+    pub fn set_log_label(&mut self, label: String) {
+        self.log_label = Some(label)
+    }
+
+    pub fn hold_outgoing(&mut self, slot: OutgoingSlot) {
+        self.outgoing = Some(slot)
+    }
+
 
     pub fn reset(&mut self) -> Result<()> {
         //// Tcp connection accepted, waiting for handshake to complete????
@@ -176,8 +305,9 @@ impl<H> Connection<H>
                 res.set_position(0);
                 self.events.remove(Ready::readable());
                 self.events.insert(Ready::writable());
-                if let Some(ref addr) = self.addresses.pop() {
-                    let sock = try!(TcpStream::connect(addr));
+                if let Some(addr) = self.addresses.pop() {
+                    let sock = try!(TcpStream::connect(&addr));
+                    self.handler.on_connecting(addr);
                     Ok(self.socket = Stream::tcp(sock))
                 } else {
                     if self.settings.panic_on_new_connection {
@@ -197,6 +327,14 @@ impl<H> Connection<H>
         self.events
     }
 
+    pub fn is_connecting(&self) -> bool {
+        self.state.is_connecting()
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state.is_open()
+    }
+
     pub fn is_client(&self) -> bool {
         match self.endpoint {
             Client(_) => true,
@@ -229,6 +367,39 @@ impl<H> Connection<H>
         self.handler.on_timeout(event)
     }
 
+    #[inline]
+    pub fn timeout_dropped(&mut self, event: Token) {
+        self.handler.on_timeout_dropped(event)
+    }
+
+    pub fn read_deadline(&self) -> Deadline {
+        Deadline::Read(self.reads)
+    }
+
+    // Close the connection if the operation didn't happen in time.
+    pub fn deadline_passed(&mut self, deadline: Deadline) -> Result<()> {
+        if !self.state.is_open() {
+            return Ok(())
+        }
+        match deadline {
+            Deadline::Read(reads) => {
+                if reads == self.reads {
+                    trace!("Read from {} timed out.", self.peer_addr());
+                    self.send_close(CloseCode::Away, "Read timed out.")?;
+                }
+            }
+            Deadline::Write => {
+                if self.queued_bytes() > 0 {
+                    // the peer isn't reading, so waiting for the output to flush is pointless
+                    trace!("Write to {} timed out.", self.peer_addr());
+                    self.send_close(CloseCode::Away, "Write timed out.")?;
+                    self.disconnect();
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn error(&mut self, err: Error) {
         match self.state {
             Connecting(_, ref mut res) => {
@@ -283,49 +454,25 @@ impl<H> Connection<H>
                         if self.settings.panic_on_internal {
                             panic!("Panicking on internal error -- {}", err);
                         }
-                        let reason = format!("{}", err);
-
-                        self.handler.on_error(err);
-                        if let Err(err) = self.send_close(CloseCode::Error, reason) {
-                            self.handler.on_error(err);
-                            self.disconnect()
-                        }
+                        self.close_on_error(err)
                     }
                     Kind::Capacity => {
                         if self.settings.panic_on_capacity {
                             panic!("Panicking on capacity error -- {}", err);
                         }
-                        let reason = format!("{}", err);
-
-                        self.handler.on_error(err);
-                        if let Err(err) = self.send_close(CloseCode::Size, reason) {
-                            self.handler.on_error(err);
-                            self.disconnect()
-                        }
+                        self.close_on_error(err)
                     }
                     Kind::Protocol => {
                         if self.settings.panic_on_protocol {
                             panic!("Panicking on protocol error -- {}", err);
                         }
-                        let reason = format!("{}", err);
-
-                        self.handler.on_error(err);
-                        if let Err(err) = self.send_close(CloseCode::Protocol, reason) {
-                            self.handler.on_error(err);
-                            self.disconnect()
-                        }
+                        self.close_on_error(err)
                     }
                     Kind::Encoding(_) => {
                         if self.settings.panic_on_encoding {
                             panic!("Panicking on encoding error -- {}", err);
                         }
-                        let reason = format!("{}", err);
-
-                        self.handler.on_error(err);
-                        if let Err(err) = self.send_close(CloseCode::Invalid, reason) {
-                            self.handler.on_error(err);
-                            self.disconnect()
-                        }
+                        self.close_on_error(err)
                     }
                     Kind::Http(_) => {
                         // This may happen if some handler writes a bad response
@@ -336,6 +483,14 @@ impl<H> Connection<H>
                     Kind::Custom(_) => {
                         self.handler.on_error(err);
                     }
+                    Kind::Url(_) => {
+                        // a new connection couldn't be made, this one is fine
+                        self.handler.on_error(err);
+                    }
+                    Kind::NotFound => {
+                        // another connection was addressed, this one is fine
+                        self.handler.on_error(err);
+                    }
                     Kind::Timer(_) => {
                         if self.settings.panic_on_timeout {
                             panic!("Panicking on timer failure -- {}", err);
@@ -360,20 +515,59 @@ impl<H> Connection<H>
         }
     }
 
+    // Report the error and start a closing handshake with the matching close code.
+    fn close_on_error(&mut self, err: Error) {
+        let code = close_code_for(&err.kind);
+        let reason = format!("{}", err);
+
+        self.handler.on_error(err);
+        if let Err(err) = self.send_close(code, reason) {
+            self.handler.on_error(err);
+            self.disconnect()
+        }
+    }
+
     pub fn disconnect(&mut self) {
         match self.state {
             RespondingClose | FinishedClose | Connecting(_, _) => (),
             _ => {
-                self.handler.on_close(CloseCode::Abnormal, "");
+                if let Some((code, reason)) = self.local_close.take() {
+                    self.handler.on_close(code, &reason);
+                } else {
+                    self.handler.on_close(CloseCode::Abnormal, "");
+                }
+                self.state = FinishedClose;
+            }
+        }
+        self.events = Ready::empty()
+    }
+
+    pub fn abort(&mut self) {
+        // a zero linger turns the close into a reset
+        if let Err(err) = self.socket.evented().set_linger(Some(Duration::from_secs(0))) {
+            trace!("Unable to reset connection to {}: {}", self.peer_addr(), err);
+        }
+        match self.state {
+            RespondingClose | FinishedClose | Connecting(_, _) => (),
+            _ => {
+                self.handler.on_close(CloseCode::Abnormal, "aborted");
             }
         }
         self.events = Ready::empty()
     }
 
     pub fn consume(self) -> H {
+        self.budget.update(self.allocated, 0);
         self.handler
     }
 
+    // Bring the budget up to date with what the buffers actually hold.
+    fn account_buffers(&mut self) {
+        let allocated = self.in_buffer.get_ref().capacity() + self.out_buffer.get_ref().capacity();
+        self.budget.update(self.allocated, allocated);
+        self.allocated = allocated;
+    }
+
 
     pub fn read(&mut self) -> Result<()> {
         if self.socket.is_negotiating() {
@@ -386,17 +580,31 @@ impl<H> Connection<H>
                 Err(Error::new(Kind::Internal, "connect state not change"))
             } else {
                 trace!("Ready to read messages from {}.", self.peer_addr());
+                let mut total = 0;
                 while let Some(len) = self.buffer_in()? {
                     trace!("read data {}", len);
-                    self.read_data()?;//read data in in_buffer
                     if len == 0 {
-                        if self.events.is_writable() {
+                        // The peer half-closed the connection without closing it first. It may
+                        // still be reading, so finish flushing any output before disconnecting.
+                        trace!("Read end of stream from {}.", self.peer_addr());
+                        self.peer_closed = true;
+                        if self.queued_bytes() > 0 {
                             self.events.remove(Ready::readable());
                         } else {
                             self.disconnect()
                         }
                         break
                     }
+                    self.reads += 1;
+                    self.read_data()?;//read data in in_buffer
+                    total += len;
+                    if let Some(max) = self.settings.max_read_bytes_per_poll {
+                        if total >= max {
+                            // the socket is still readable, so it's picked up again next time
+                            trace!("Read {} bytes from {}, leaving the rest for later.", total, self.peer_addr());
+                            break
+                        }
+                    }
                 }
                 Ok(())
             }
@@ -408,7 +616,11 @@ impl<H> Connection<H>
         let mut buffer = Vec::with_capacity(self.in_buffer.get_ref().len());
         match self.in_buffer.read_to_end(&mut buffer) {
             Ok(data_size) => {
-                let msg = Message::text((String::from_utf8(buffer).map_err(|err| err.utf8_error()))?);
+                // there is no opcode to go by, so data that isn't valid UTF-8 is passed on intact
+                let msg = match String::from_utf8(buffer) {
+                    Ok(text) => Message::Text(text),
+                    Err(err) => Message::Binary(err.into_bytes()),
+                };
                 self.handler.on_message(msg)?;
                 Ok(())
             }
@@ -421,18 +633,28 @@ impl<H> Connection<H>
             trace!("Performing TLS negotiation on {}.", self.peer_addr());
             self.socket.clear_negotiating()
         } else {
-            let res = if self.state.is_connecting() {
+            let res = if self.state.is_connecting() && self.is_client() {
+                // the non-blocking connect finished, find out whether it succeeded
+                if let Some(err) = self.socket.evented().take_error()? {
+                    return Err(Error::from(err))
+                }
+                trace!("Connected to {}.", self.peer_addr());
+                self.events.remove(Ready::writable());
+                self.open()
+            } else if self.state.is_connecting() {
                 trace!("connect state not change {}.", self.peer_addr());
                 Err(Error::new(Kind::Internal, "connect state not change"))
             } else {
                 trace!("Ready to write messages to {}.", self.peer_addr());
 
+                self.expire_messages();
+
                 // Start out assuming that this write will clear the whole buffer
                 self.events.remove(Ready::writable());
                 //写的数据，返回写的长度，错误码已经返回值的形式，发送给对方。
                 trace!("---------------======postions {:?}-", self.out_buffer.position());
 
-                if let Some(len) = try!(self.socket.try_write_buf(&mut self.out_buffer)) {
+                if let Some(len) = self.write_out()? {
                     trace!("Wrote {} bytes to {}", len, self.peer_addr());
                     if len == 0 {
                         match self.state {
@@ -444,6 +666,20 @@ impl<H> Connection<H>
                     }
                 }
 
+                self.notify_sent()?;
+
+                if self.peer_closed && self.queued_bytes() == 0 {
+                    trace!("Flushed remaining output to half-closed {}.", self.peer_addr());
+                    self.disconnect();
+                    return Ok(())
+                }
+
+                if self.state.is_awaiting_close() && self.queued_bytes() == 0 {
+                    trace!("Flushed remaining output to closing {}.", self.peer_addr());
+                    self.disconnect();
+                    return Ok(())
+                }
+
                 // Check if there is more to write so that the connection will be rescheduled
                 Ok(self.check_events())
             };
@@ -457,33 +693,196 @@ impl<H> Connection<H>
         }
     }
 
+    // Write the next piece of output, either from out_buffer or from the shared chunk that is due.
+    fn write_out(&mut self) -> Result<Option<usize>> {
+        let pos = self.out_buffer.position() as usize;
+        if let Some(at) = self.shared.front().map(|chunk| chunk.at) {
+            if at == pos {
+                let res = {
+                    let chunk = &mut self.shared[0];
+                    let res = self.socket.try_write_buf(&mut Cursor::new(&chunk.data[chunk.pos..]))?;
+                    chunk.pos += res.unwrap_or(0);
+                    res
+                };
+                if self.shared[0].pos == self.shared[0].data.len() {
+                    self.shared.pop_front();
+                }
+                return Ok(res)
+            }
+            // only write up to the point where the chunk is due
+            let res = self.socket.try_write_buf(&mut Cursor::new(&self.out_buffer.get_ref()[pos..at]))?;
+            self.out_buffer.set_position((pos + res.unwrap_or(0)) as u64);
+            return Ok(res)
+        }
+        Ok(self.socket.try_write_buf(&mut self.out_buffer)?)
+    }
+
     pub fn send_message(&mut self, msg: Message) -> Result<()> {
+        self.buffer_message(msg).map(|_| ())
+    }
+
+    // Whether the message was buffered rather than dropped because the connection is closing.
+    pub fn send_message_if_open(&mut self, msg: Message) -> Result<bool> {
+        self.buffer_message(msg).map(|buffered| buffered.is_some())
+    }
+
+    pub fn send_messages<I>(&mut self, msgs: I) -> Result<()>
+        where I: IntoIterator<Item=Message>
+    {
+        for msg in msgs {
+            self.send_message(msg)?
+        }
+        Ok(())
+    }
+
+    pub fn send_message_with_notify(&mut self, msg: Message, token: Token) -> Result<()> {
+        if let Some((_, end)) = self.buffer_message(msg)? {
+            self.notify.push_back((end, token));
+        }
+        Ok(())
+    }
+
+    // Tell the handler about every message that has now been written completely.
+    fn notify_sent(&mut self) -> Result<()> {
+        let pos = self.out_buffer.position() as usize;
+        while let Some(&(end, token)) = self.notify.front() {
+            if end > pos {
+                break
+            }
+            self.notify.pop_front();
+            self.handler.on_sent(token)?;
+        }
+        Ok(())
+    }
+
+    pub fn send_message_with_deadline(&mut self, msg: Message, deadline: Instant) -> Result<()> {
+        if let Some((start, end)) = self.buffer_message(msg)? {
+            self.deadlines.push_back((start, end, deadline));
+        }
+        Ok(())
+    }
+
+    // Buffer data that is shared with other connections without copying it.
+    pub fn send_shared(&mut self, data: Arc<Vec<u8>>) -> Result<()> {
+        if self.state.is_closing() {
+            trace!("Connection is closing. Ignoring request to send {} shared bytes to {}.",
+                   data.len(),
+                   self.peer_addr());
+            return Ok(());
+        }
+
+        if let Some(max) = self.settings.max_out_buffer_bytes {
+            if self.queued_bytes() + data.len() > max {
+                return self.drop_slow_reader(max);
+            }
+        }
+
+        trace!("Buffering {} shared bytes to {}", data.len(), self.peer_addr());
+        self.shared.push_back(SharedChunk {
+            at: self.out_buffer.get_ref().len(),
+            data: data,
+            pos: 0,
+        });
+        self.check_events();
+        Ok(())
+    }
+
+    // Returns the range of out_buffer that the message was written to, if it was buffered.
+    fn buffer_message(&mut self, msg: Message) -> Result<Option<(usize, usize)>> {
         if self.state.is_closing() {
             trace!("Connection is closing. Ignoring request to send message {:?} to {}.",
                    msg,
                    self.peer_addr());
-            return Ok(());
+            return Ok(None);
         }
 
         let opcode = msg.opcode();
         trace!("Message opcode {:?}", opcode);
         let data = msg.into_data();
 
+        if let Some(max) = self.settings.max_out_buffer_bytes {
+            if self.queued_bytes() + data.len() > max {
+                return self.drop_slow_reader(max).map(|_| None);
+            }
+        }
+
         self.check_buffer_out(&data)?;//检查输出buffer容量，不够则扩充容量。
         trace!("Buffering frame to {} : {:?}", self.peer_addr(), data);
         //TODO 写数据。
         let pos = self.out_buffer.position();
-        self.out_buffer.seek(SeekFrom::End(0))?;
+        let start = self.out_buffer.seek(SeekFrom::End(0))? as usize;
         match self.out_buffer.write(&data) {
             Ok(buffer_size) => {
-                //TODO
                 self.out_buffer.seek(SeekFrom::Start(pos))?;
-                Ok(self.check_events())
+                self.check_events();
+                Ok(Some((start, start + buffer_size)))
             }
             Err(err) => Err(Error::from(err))
         }
     }
 
+    // Drop any messages whose deadline has passed before we started writing them. Messages that
+    // are already partially written are always finished.
+    fn expire_messages(&mut self) {
+        let pos = self.out_buffer.position() as usize;
+        self.deadlines.retain(|&(start, _, _)| start >= pos);
+        if self.deadlines.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut removed = 0;
+        let mut expired = Vec::new();
+        let mut pending = VecDeque::with_capacity(self.deadlines.len());
+        for (start, end, deadline) in self.deadlines.drain(..) {
+            let (start, end) = (start - removed, end - removed);
+            if deadline <= now {
+                expired.push(self.out_buffer.get_mut().drain(start..end).collect::<Vec<u8>>());
+                for chunk in self.shared.iter_mut() {
+                    if chunk.at >= end {
+                        chunk.at -= end - start;
+                    }
+                }
+                for notify in self.notify.iter_mut() {
+                    if notify.0 >= end {
+                        notify.0 -= end - start;
+                    }
+                }
+                removed += end - start;
+            } else {
+                pending.push_back((start, end, deadline));
+            }
+        }
+        self.deadlines = pending;
+
+        for data in expired {
+            trace!("Dropping expired message of {} bytes to {}.", data.len(), self.peer_addr());
+            self.handler.on_message_expired(data);
+        }
+    }
+
+
+    pub fn queued_bytes(&self) -> usize {
+        let shared: usize = self.shared.iter().map(|chunk| chunk.data.len() - chunk.pos).sum();
+        self.out_buffer.get_ref().len() - self.out_buffer.position() as usize + shared
+    }
+
+    // The peer is not draining what we already have buffered for it, so there is no point in
+    // waiting for a closing handshake either.
+    fn drop_slow_reader(&mut self, max: usize) -> Result<()> {
+        if self.settings.panic_on_capacity {
+            panic!("Panicking on capacity error -- output buffer limit of {} bytes reached.", max);
+        }
+        let err = Error::new(
+            Kind::Capacity,
+            format!("Output buffer for {} would exceed {} bytes.", self.peer_addr(), max));
+        let reason = format!("{}", err);
+
+        self.handler.on_error(err);
+        self.send_close(CloseCode::Policy, reason)?;
+        self.disconnect();
+        Ok(())
+    }
 
     #[inline]
     pub fn send_close<R>(&mut self, code: CloseCode, reason: R) -> Result<()>
@@ -502,9 +901,15 @@ impl<H> Connection<H>
                 return Ok(self.check_events());
             }
             // We are initiating a closing handshake.
-            Open => self.state = AwaitingClose,
+            Open => {
+                self.state = AwaitingClose;
+                self.local_close = Some((code, reason.borrow().to_owned()));
+            }
+            // A client connection that is still connecting has nothing to close, give up on it.
             Connecting(_, _) => {
-                debug_assert!(false, "Attempted to close connection while not yet open.")
+                trace!("Giving up on connecting to {}.", self.peer_addr());
+                self.disconnect();
+                return Ok(())
             }
         }
 
@@ -517,15 +922,28 @@ impl<H> Connection<H>
 
         trace!("Connection to {} is now closing.", self.peer_addr());
 
+        // There is no close frame to wait on, so the close is done once our output is flushed
+        if let AwaitingClose = self.state {
+            if self.queued_bytes() == 0 {
+                self.disconnect();
+                return Ok(())
+            }
+        }
+
         Ok(self.check_events())
     }
 
     //通过事件来产生读写的，都是以回调的方式，
     //思想:是事先准备好数据,然后设置监听事件,然后进行回调处理.
+    // Writable interest is registered if and only if there is output left to write, in every
+    // state. Closing writes nothing of its own here, so a closing connection with an empty
+    // out_buffer only waits to be readable instead of waking the event loop to write nothing.
     fn check_events(&mut self) {
         if !self.state.is_connecting() {
-            self.events.insert(Ready::readable());
-            if self.out_buffer.position() < self.out_buffer.get_ref().len() as u64 {
+            if !self.peer_closed {
+                self.events.insert(Ready::readable());
+            }
+            if self.queued_bytes() > 0 {
                 trace!("check_event----{:?}-----{:?}-", self.out_buffer.get_ref().len(), self.out_buffer.get_ref());
                 self.events.insert(Ready::writable());
             }
@@ -536,16 +954,37 @@ impl<H> Connection<H>
     fn check_buffer_out(&mut self, frame: &Vec<u8>) -> Result<()> {
         if self.out_buffer.get_ref().capacity() <= self.out_buffer.get_ref().len() + frame.len() {
             // extend
+            let pos = self.out_buffer.position() as usize;
             let mut new = Vec::with_capacity(self.out_buffer.get_ref().capacity());
-            new.extend(&self.out_buffer.get_ref()[self.out_buffer.position() as usize..]);
-            if new.len() == new.capacity() {
-                if self.settings.out_buffer_grow {
-                    new.reserve(self.settings.out_buffer_capacity)
-                } else {
-                    return Err(Error::new(Kind::Capacity, "Maxed out output buffer for connection."));
+            new.extend(&self.out_buffer.get_ref()[pos..]);
+            // messages that have started leaving can't expire anymore, the rest move with the data
+            self.deadlines.retain(|&(start, _, _)| start >= pos);
+            for deadline in self.deadlines.iter_mut() {
+                deadline.0 -= pos;
+                deadline.1 -= pos;
+            }
+            for chunk in self.shared.iter_mut() {
+                chunk.at -= pos;
+            }
+            for notify in self.notify.iter_mut() {
+                notify.0 -= pos;
+            }
+            if new.len() == new.capacity() && !self.settings.out_buffer_grow {
+                return Err(Error::new(Kind::Capacity, "Maxed out output buffer for connection."));
+            }
+            if new.len() + frame.len() > new.capacity() {
+                // make room for the whole message, so writing it doesn't grow the buffer past the
+                // budget
+                let additional = cmp::max(self.settings.out_buffer_capacity, frame.len());
+                let growth = new.len() + additional - self.out_buffer.get_ref().capacity();
+                if !self.budget.allows(growth) {
+                    return Err(Error::new(Kind::Capacity, "Exhausted the buffer budget of the WebSocket."));
                 }
+                // an amortized reserve could take more than the budget allows
+                new.reserve_exact(additional)
             }
             self.out_buffer = Cursor::new(new);
+            self.account_buffers();
         }
         Ok(())
     }
@@ -553,25 +992,151 @@ impl<H> Connection<H>
     fn buffer_in(&mut self) -> Result<Option<usize>> {
         //input buffer
         trace!("Reading buffer for connection to {}.", self.peer_addr());
-        if let Some(len) = self.socket.try_read_buf(self.in_buffer.get_mut())? {
-            trace!("try read buffer len {:?}, data {:?}", len, self.in_buffer.get_ref());
-            if self.in_buffer.get_ref().len() == self.in_buffer.get_ref().capacity() {
-                // extend
-                let mut new = Vec::with_capacity(self.in_buffer.get_ref().capacity());
-                new.extend(&self.in_buffer.get_ref()[self.in_buffer.position() as usize..]);
-
-                if new.len() == new.capacity() {
-                    if self.settings.in_buffer_grow {
-                        new.reserve(self.settings.in_buffer_capacity);
-                    } else {
-                        return Err(Error::new(Kind::Capacity, "Maxed out input buffer for connection."));
-                    }
+        if self.in_buffer.get_ref().len() == self.in_buffer.get_ref().capacity() {
+            // make room before reading, a read into a full buffer would look like end of stream
+            let mut new = Vec::with_capacity(self.in_buffer.get_ref().capacity());
+            new.extend(&self.in_buffer.get_ref()[self.in_buffer.position() as usize..]);
+
+            if new.len() == new.capacity() {
+                if !self.settings.in_buffer_grow {
+                    return Err(Error::new(Kind::Capacity, "Maxed out input buffer for connection."));
                 }
-                self.in_buffer = Cursor::new(new);
+                if !self.budget.allows(self.settings.in_buffer_capacity) {
+                    return Err(Error::new(Kind::Capacity, "Exhausted the buffer budget of the WebSocket."));
+                }
+                // an amortized reserve could double the buffer past the budget
+                new.reserve_exact(self.settings.in_buffer_capacity);
             }
+            self.in_buffer = Cursor::new(new);
+            self.account_buffers();
+        }
+        if let Some(len) = self.socket.try_read_buf(self.in_buffer.get_mut())? {
+            trace!("try read buffer len {:?}, data {:?}", len, self.in_buffer.get_ref());
             Ok(Some(len))
         } else {
             Ok(None)
         }
     }
 }
+
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use std::net;
+    use std::time::Duration;
+
+    use mio::{Events, Poll, PollOpt};
+
+    use super::*;
+
+    struct H;
+
+    impl Handler for H {}
+
+    fn connection() -> (Connection<H>, net::TcpStream) {
+        connection_with(Settings::default())
+    }
+
+    fn connection_with(settings: Settings) -> (Connection<H>, net::TcpStream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let sock = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        (Connection::new(Token(1), sock, H, settings, 0, BufferBudget::new(None)), peer)
+    }
+
+    #[test]
+    fn budget_released_on_consume() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let sock = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let budget = BufferBudget::new(Some(1 << 20));
+        let conn = Connection::new(Token(1), sock, H, Settings::default(), 0, budget.clone());
+        let settings = Settings::default();
+        assert_eq!(budget.used(), settings.in_buffer_capacity + settings.out_buffer_capacity);
+
+        conn.consume();
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn growth_stays_within_budget() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let sock = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        // the initial buffers, with room for the input buffer to grow twice
+        let budget = BufferBudget::new(Some(1024 + 3 * 1024));
+        let mut conn = Connection::new(Token(1), sock, H, Settings {
+            in_buffer_capacity: 1024,
+            out_buffer_capacity: 1024,
+            ..Settings::default()
+        }, 0, budget.clone());
+        conn.as_server().unwrap();
+        conn.open().unwrap();
+
+        peer.write_all(&[b'x'; 8 << 10]).unwrap();
+        ::std::thread::sleep(Duration::from_millis(50));
+        // fill the input buffer without consuming it, so it has to grow, and doubling it the
+        // second time would go past the budget
+        for _ in 0..3 {
+            conn.buffer_in().unwrap();
+            assert!(budget.used() <= 4096, "{} bytes allocated", budget.used());
+        }
+        match conn.buffer_in() {
+            Err(Error { kind: Kind::Capacity, .. }) => (),
+            res => panic!("Expected a Capacity error, got {:?}", res),
+        }
+        assert_eq!(budget.used(), 4096);
+    }
+
+    #[test]
+    fn read_limited_per_poll() {
+        let (mut conn, mut peer) = connection_with(Settings {
+            in_buffer_capacity: 1024,
+            in_buffer_grow: true,
+            max_read_bytes_per_poll: Some(4096),
+            ..Settings::default()
+        });
+        conn.as_server().unwrap();
+        conn.open().unwrap();
+
+        peer.write_all(&[b'x'; 32 << 10]).unwrap();
+        ::std::thread::sleep(Duration::from_millis(50));
+        conn.read().unwrap();
+        assert!(conn.reads < 32, "read everything in {} reads", conn.reads);
+        // still interested in the rest
+        assert!(conn.events().is_readable());
+    }
+
+    #[test]
+    fn writable_only_with_output() {
+        let (mut conn, peer) = connection();
+        conn.as_server().unwrap();
+        conn.open().unwrap();
+        assert_eq!(conn.events(), Ready::readable());
+
+        conn.send_message(Message::text("hello")).unwrap();
+        assert_eq!(conn.events(), Ready::readable() | Ready::writable());
+        while conn.events().is_writable() {
+            conn.write().unwrap();
+        }
+        assert_eq!(conn.events(), Ready::readable());
+
+        // the event loop isn't woken while waiting for the peer
+        let poll = Poll::new().unwrap();
+        poll.register(conn.socket(), conn.token(), conn.events(), PollOpt::edge() | PollOpt::oneshot()).unwrap();
+        let mut events = Events::with_capacity(16);
+        assert_eq!(poll.poll(&mut events, Some(Duration::from_millis(200))).unwrap(), 0);
+
+        conn.send_message(Message::text("bye")).unwrap();
+        conn.send_close(CloseCode::Normal, "").unwrap();
+        assert_eq!(conn.events(), Ready::readable() | Ready::writable());
+        // messages are ignored while closing, so only the earlier output is left to write
+        conn.send_message(Message::text("ignored")).unwrap();
+        assert_eq!(conn.queued_bytes(), 3);
+        while conn.events().is_writable() {
+            conn.write().unwrap();
+        }
+        // once flushed the close is done and there is nothing left to wait for
+        assert!(conn.events().is_empty());
+    }
+}
@@ -99,6 +99,17 @@ pub trait Factory {
     /// state that was not internally tracked by the handler.
     #[inline]
     fn connection_lost(&mut self, _: Self::Handler) {}
+
+    /// Called when the event loop starts running, before any connection is accepted or made.
+    /// The WebSocket is already bound at this point, so this is a good place to announce a
+    /// server that is ready, for instance to a service discovery system.
+    #[inline]
+    fn on_loop_start(&mut self) {}
+
+    /// Called once the event loop has stopped running, after it was shut down or, for a
+    /// WebSocket that only makes connections, after the last of them was dropped.
+    #[inline]
+    fn on_loop_stop(&mut self) {}
 }
 
 impl<F, H> Factory for F
@@ -1,11 +1,25 @@
+use std::net::SocketAddr;
+use std::cell::Cell;
+
 use url;
 use log::LogLevel::Error as ErrorLevel;
 
 use message::Message;
 use protocol::CloseCode;
-use result::{Result, Error, Kind};
+use result::{Result, Error, Severity};
 use util::{Token, Timeout};
 
+thread_local! {
+    // Whether the default on_error prints to stdout when no logger is enabled. The event loop
+    // sets it from its settings for the thread it runs on, which is where handlers are called.
+    static STDOUT_FALLBACK: Cell<bool> = const { Cell::new(true) };
+}
+
+// Returns the previous value, so that it can be restored.
+pub fn set_stdout_fallback(enabled: bool) -> bool {
+    STDOUT_FALLBACK.with(|fallback| fallback.replace(enabled))
+}
+
 /// The core trait of this library.
 /// Implementing this trait provides the business logic of the WebSocket application.
 pub trait Handler {
@@ -19,8 +33,22 @@ pub trait Handler {
 
     // WebSocket events
 
+    /// Called on client connections when the TCP connect to `addr` has been initiated, before
+    /// the connection is open. This can be used to show progress or to start a connect timeout.
+    /// Once the connect completes `on_open` is called, and if it fails `on_error` is called
+    /// instead, after another address of the host has been tried if there is one.
+    #[inline]
+    fn on_connecting(&mut self, addr: SocketAddr) {
+        debug!("Connecting to {}", addr);
+    }
+
     /// Called when the WebSocket handshake is successful and the connection is open for sending
     /// and receiving messages.
+    ///
+    /// Returning an error rejects the connection. The error is passed to `on_error`, the
+    /// connection is closed with `CloseCode::Policy` and the error as the reason, and then the
+    /// socket is shut down. This happens for every kind of error, regardless of the `panic_on_*`
+    /// settings.
     fn on_open(&mut self) -> Result<()> {
         //        if let Some(addr) = try!(shake.remote_addr()) {
         //            debug!("Connection with {} now open", addr);
@@ -34,25 +62,47 @@ pub trait Handler {
         Ok(())
     }
 
+    /// Called with the data of a message sent with `Sender::send_with_deadline` that was dropped
+    /// because it had not begun transmission by its deadline.
+    #[inline]
+    fn on_message_expired(&mut self, data: Vec<u8>) {
+        debug!("Dropped expired message of {} bytes", data.len());
+    }
+
+    /// Called with the token given to `Sender::send_and_notify` once all of that message's bytes
+    /// have been written to the socket. This can be used to start a response timeout only after
+    /// a request has actually gone out.
+    #[inline]
+    fn on_sent(&mut self, event: Token) -> Result<()> {
+        debug!("Handler received sent token: {:?}", event);
+        Ok(())
+    }
+
     /// Called any time this endpoint receives a close control frame.
     /// This may be because the other endpoint is initiating a closing handshake,
     /// or it may be the other endpoint confirming the handshake initiated by this endpoint.
+    /// When this endpoint started the close with `Sender::close` or `close_with_reason`, the code
+    /// and reason given there are reported once the close completes.
     fn on_close(&mut self, code: CloseCode, reason: &str) {
         debug!("Connection closing due to ({:?}) {}", code, reason);
     }
 
     /// Called when an error occurs on the WebSocket.
+    ///
+    /// By default, errors that come with normal operation, such as a connection reset or a peer
+    /// sending malformed data, are only logged at debug level. See `Error::severity`. Other
+    /// errors are also printed to stdout if no logger is enabled, unless
+    /// `Settings::error_stdout_fallback` is turned off.
     fn on_error(&mut self, err: Error) {
-        // Ignore connection reset errors by default, but allow library clients to see them by
+        // Keep expected errors quiet by default, but allow library clients to see them by
         // overriding this method if they want
-        if let Kind::Io(ref err) = err.kind {
-            if let Some(104) = err.raw_os_error() {
-                return;
-            }
+        if err.severity() == Severity::Expected {
+            debug!("{:?}", err);
+            return;
         }
 
         error!("{:?}", err);
-        if !log_enabled!(ErrorLevel) {
+        if !log_enabled!(ErrorLevel) && STDOUT_FALLBACK.with(|fallback| fallback.get()) {
             println!("Encountered an error: {}\nEnable a logger to see more information.", err);
         }
     }
@@ -95,6 +145,14 @@ pub trait Handler {
     }
 
 
+    /// Called for each timeout that was still scheduled for this connection when the connection
+    /// was dropped. The timeout won't fire anymore, so this is the place to clean up anything
+    /// that was waiting on it.
+    #[inline]
+    fn on_timeout_dropped(&mut self, event: Token) {
+        debug!("Handler dropped timeout token: {:?}", event);
+    }
+
     #[inline]
     fn on_new_timeout(&mut self, _: Token, _: Timeout) -> Result<()> {
         // default implementation discards the timeout handle
@@ -1,8 +1,21 @@
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::borrow::Borrow;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::usize;
+use std::cmp;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
 use std::io::{ErrorKind, Error as IoError};
+#[cfg(unix)]
+use std::os::unix::io::{RawFd, FromRawFd, IntoRawFd};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::net;
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use libc;
 
 use mio;
 use mio::{
@@ -14,14 +27,19 @@ use mio::{
 use mio::tcp::{TcpListener, TcpStream};
 
 use url::Url;
+use net2::TcpBuilder;
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
 
 
-use communication::{Sender, Signal, Command};
+use communication::{Sender, Signal, Command, Diagnostics, OutgoingLimit, OutgoingSlot};
 use result::{Result, Error, Kind};
-use connection::Connection;
+use message::Message;
+use connection::{Connection, Deadline, BufferBudget};
 use factory::Factory;
+use handler::{Handler as WsHandler, set_stdout_fallback};
 use util::Slab;
-use super::Settings;
+use super::{Settings, AcceptPolicy, DuplicateLabels};
 
 const QUEUE: Token = Token(usize::MAX - 3);//接受数据方监听的fd,
 const TIMER: Token = Token(usize::MAX - 4);
@@ -31,30 +49,150 @@ const SYSTEM: Token = Token(usize::MAX - 6);
 type Conn<F> = Connection<<F as Factory>::Handler>;
 
 const MAX_EVENTS: usize = 1024;
-const MESSAGES_PER_TICK: usize = 256;
-const TIMER_TICK_MILLIS: u64 = 100;
 const TIMER_WHEEL_SIZE: usize = 1024;
-const TIMER_CAPACITY: usize = 65_536;
 
 #[cfg(not(windows))]
 const CONNECTION_REFUSED: i32 = 111;
 #[cfg(windows)]
 const CONNECTION_REFUSED: i32 = 61;
 
+// ENFILE, EMFILE and ENOMEM
+#[cfg(not(windows))]
+const RESOURCES_EXHAUSTED: [i32; 3] = [23, 24, 12];
+// WSAEMFILE and WSAENOBUFS
+#[cfg(windows)]
+const RESOURCES_EXHAUSTED: [i32; 2] = [10024, 10055];
+
+const ACCEPT_BACKOFF_MILLIS: u64 = 100;
+// The same backlog mio uses for its own listeners
+const LISTEN_BACKLOG: i32 = 1024;
+
+// Accept errors that are expected to clear up on their own once connections are dropped
+fn is_transient_accept_error(err: &IoError) -> bool {
+    err.raw_os_error().map(|errno| RESOURCES_EXHAUSTED.contains(&errno)).unwrap_or(false)
+}
+
+// Bind a listener with the socket options that TcpListener::bind doesn't set
+fn build_listener(addr: &SocketAddr, settings: &Settings) -> ::std::io::Result<net::TcpListener> {
+    let sock = match *addr {
+        SocketAddr::V4(..) => TcpBuilder::new_v4(),
+        SocketAddr::V6(..) => TcpBuilder::new_v6(),
+    }?;
+    sock.reuse_address(true)?;
+    if settings.tcp_reuse_port {
+        set_reuse_port(&sock)?;
+    }
+    sock.bind(addr)?;
+    if settings.tcp_fastopen {
+        set_fastopen(&sock, settings.tcp_fastopen_backlog);
+    }
+    sock.listen(LISTEN_BACKLOG)
+}
+
+// Let the listener share its address with other listeners that set SO_REUSEPORT
+#[cfg(unix)]
+fn set_reuse_port(sock: &TcpBuilder) -> ::std::io::Result<()> {
+    sock.reuse_port(true).map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_: &TcpBuilder) -> ::std::io::Result<()> {
+    Err(IoError::new(ErrorKind::Other, "SO_REUSEPORT is only supported on Unix"))
+}
+
+// Fast Open is only an optimization, so the listener goes ahead without it if it's refused
+#[cfg(target_os = "linux")]
+fn set_fastopen(sock: &TcpBuilder, backlog: usize) {
+    let qlen = cmp::min(backlog, libc::c_int::max_value() as usize) as libc::c_int;
+    let value: *const libc::c_int = &qlen;
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            value as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret == -1 {
+        warn!("Unable to enable TCP Fast Open: {}", IoError::last_os_error());
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fastopen(_: &TcpBuilder, _: usize) {
+    warn!("TCP Fast Open is only supported on Linux, listening without it.");
+}
+
+// Whether the fd refers to a socket that has had listen called on it
+#[cfg(unix)]
+fn is_listening(fd: RawFd) -> ::std::io::Result<bool> {
+    let mut accepting: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let value: *mut libc::c_int = &mut accepting;
+    let ret = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN, value as *mut libc::c_void, &mut len)
+    };
+    if ret == -1 {
+        return Err(IoError::last_os_error())
+    }
+    Ok(accepting != 0)
+}
+
+// Apply the requested socket buffer sizes, which are only ever a hint to the OS
+fn set_buffer_sizes(sock: &TcpStream, settings: &Settings) {
+    if let Some(size) = settings.tcp_send_buffer_size {
+        match sock.set_send_buffer_size(size).and_then(|_| sock.send_buffer_size()) {
+            Ok(actual) if actual < size => {
+                warn!("Requested a send buffer of {} bytes but the socket has {} bytes.", size, actual)
+            }
+            Ok(_) => (),
+            Err(err) => warn!("Unable to set the send buffer size to {} bytes: {}", size, err),
+        }
+    }
+    if let Some(size) = settings.tcp_recv_buffer_size {
+        match sock.set_recv_buffer_size(size).and_then(|_| sock.recv_buffer_size()) {
+            Ok(actual) if actual < size => {
+                warn!("Requested a receive buffer of {} bytes but the socket has {} bytes.", size, actual)
+            }
+            Ok(_) => (),
+            Err(err) => warn!("Unable to set the receive buffer size to {} bytes: {}", size, err),
+        }
+    }
+}
+
+// Forget that the connection with `token` is tagged with `label`.
+fn unlabel(labels: &mut HashMap<String, Vec<Token>>, label: &str, token: Token) {
+    let empty = if let Some(tokens) = labels.get_mut(label) {
+        tokens.retain(|&tok| tok != token);
+        tokens.is_empty()
+    } else {
+        false
+    };
+    if empty {
+        labels.remove(label);
+    }
+}
+
+// Create a handler for a new connection, turning a panic in the factory into an error if asked to
+fn build_handler<F, M>(factory: &mut F, catch_panics: bool, make: M) -> Result<F::Handler>
+    where F: Factory, M: FnOnce(&mut F) -> F::Handler
+{
+    if !catch_panics {
+        return Ok(make(factory))
+    }
+    panic::catch_unwind(AssertUnwindSafe(|| make(factory))).map_err(|_| {
+        Error::new(Kind::Internal, "Factory panicked while creating a connection handler.")
+    })
+}
+
+// Accepts a URL such as "ws://example.com", or a plain host and port such as "example.com:80".
 fn url_to_addrs(url: &String) -> Result<Vec<SocketAddr>> {
-    //    let host = url.host_str();
-    
-    //    if host.is_none() || (url.scheme() != "ws" && url.scheme() != "wss") {
-    //        return Err(Error::new(Kind::Internal, format!("Not a valid websocket url: {}", url)));
-    //    }
-    //    let host = host.unwrap();
-    //
-    //    let port = url.port_or_known_default().unwrap_or(80);
-    //    let mut addrs = try!((&host[..], port).to_socket_addrs()).collect::<Vec<SocketAddr>>();
-    //    addrs.dedup();
-    
-    //TODO TCP address
-    let addrs = Vec::from(url.to_socket_addrs().unwrap().as_slice());
+    let mut addrs = if url.contains("://") {
+        Url::parse(url)?.to_socket_addrs()?.collect::<Vec<SocketAddr>>()
+    } else {
+        url.to_socket_addrs()?.collect::<Vec<SocketAddr>>()
+    };
+    addrs.dedup();
     Ok(addrs)
 }
 
@@ -76,6 +214,16 @@ impl State {
 pub struct Timeout {
     connection: Token,
     event: Token,
+    id: u64,
+    // set for deadlines, along with the id of the connection they were set for
+    deadline: Option<(u32, Deadline)>,
+}
+
+// A broadcast that is still being buffered, batch by batch, on the connections that were open
+// when it was sent.
+struct PendingBroadcast {
+    data: Arc<Vec<u8>>,
+    remaining: Vec<(Token, u32)>,
 }
 
 pub struct Handler<F>
@@ -89,7 +237,29 @@ pub struct Handler<F>
     queue_tx: mio::channel::SyncSender<Command>,
     queue_rx: mio::channel::Receiver<Command>,
     timer: mio::timer::Timer<Timeout>,
-    next_connection_id: u32
+    next_connection_id: u32,
+    broadcasts: VecDeque<PendingBroadcast>,
+    // whether a shutdown signal was handled in the current run
+    shutting_down: bool,
+    budget: BufferBudget,
+    pending_timeouts: usize,
+    // scheduled timeouts by the connection they are for, so they can be cancelled together
+    timeouts: HashMap<Token, Vec<(u64, mio::timer::Timeout)>>,
+    next_timeout_id: u64,
+    last_poll_duration: Duration,
+    iterations: u64,
+    // whether the listener is registered with the poll
+    accepting: bool,
+    // whether accepting is paused after a transient accept error
+    accept_backoff: bool,
+    // whether accepting was stopped with Sender::stop_accepting
+    accept_stopped: bool,
+    outgoing: Arc<OutgoingLimit>,
+    // the connections tagged with each label, see Sender::send_to_label
+    labels: HashMap<String, Vec<Token>>,
+    // the connections removed in the current iteration, whose tokens may have been reused by a
+    // new connection since the events were polled
+    removed: Vec<Token>,
 }
 
 
@@ -99,34 +269,99 @@ impl<F> Handler<F>
     pub fn new(factory: F, settings: Settings) -> Handler<F> {
         let (tx, rx) = mio::channel::sync_channel(settings.max_connections * settings.queue_size);
         let timer = mio::timer::Builder::default()
-            .tick_duration(Duration::from_millis(TIMER_TICK_MILLIS))
+            .tick_duration(Duration::from_millis(settings.timer_tick_ms))
             .num_slots(TIMER_WHEEL_SIZE)
-            .capacity(TIMER_CAPACITY)
+            .capacity(settings.timer_capacity)
             .build();
         Handler {
             listener: None,
-            connections: Slab::with_capacity(settings.max_connections),
+            connections: Slab::with_capacity(cmp::min(settings.initial_connection_capacity, settings.max_connections)),
             factory: factory,
             settings: settings,
             state: State::Inactive,
             queue_tx: tx,
             queue_rx: rx,
             timer: timer,
-            next_connection_id: 0
+            next_connection_id: 0,
+            broadcasts: VecDeque::new(),
+            shutting_down: false,
+            budget: BufferBudget::new(settings.global_buffer_budget),
+            pending_timeouts: 0,
+            timeouts: HashMap::new(),
+            next_timeout_id: 0,
+            last_poll_duration: Duration::from_millis(0),
+            iterations: 0,
+            accepting: false,
+            accept_backoff: false,
+            accept_stopped: false,
+            outgoing: OutgoingLimit::new(settings.max_outgoing_connections),
+            labels: HashMap::new(),
+            removed: Vec::new(),
+        }
+    }
+    
+    // Make room for one more connection, doubling the slab up to `max_connections`.
+    fn reserve_connection(&mut self) {
+        let capacity = self.connections.capacity();
+        if !self.connections.has_available() && capacity < self.settings.max_connections {
+            let additional = cmp::min(cmp::max(capacity, 1), self.settings.max_connections - capacity);
+            trace!("Growing connections from {} to {}", capacity, capacity + additional);
+            self.connections.reserve_exact(additional);
         }
     }
     
     pub fn sender(&self) -> Sender {
-        Sender::new(ALL, self.queue_tx.clone(), 0)
+        Sender::new(ALL, self.queue_tx.clone(), 0).outgoing(self.outgoing.clone())
     }
     
     pub fn listen(&mut self, poll: &mut Poll, addr: &SocketAddr) -> Result<&mut Handler<F>> {
         debug_assert!(self.listener.is_none(), "Attempted to listen for connections from two addresses on the same websocket.");
         
+        if self.settings.tcp_reuse_port || self.settings.tcp_fastopen {
+            let listener = build_listener(addr, &self.settings)?;
+            return self.listen_tcp(poll, listener)
+        }
         let tcp = TcpListener::bind(addr)?;
         // TODO: consider net2 in order to set reuse_addr
+        self.adopt(poll, tcp)
+    }
+
+    #[cfg(unix)]
+    pub fn listen_fd(&mut self, poll: &mut Poll, fd: RawFd) -> Result<&mut Handler<F>> {
+        debug_assert!(self.listener.is_none(), "Attempted to listen for connections from two sockets on the same websocket.");
+
+        if !is_listening(fd)? {
+            return Err(Error::from(IoError::new(
+                ErrorKind::InvalidInput,
+                format!("File descriptor {} is not a listening socket", fd))))
+        }
+        // the caller keeps the fd until it is adopted, so it is handed back on any error
+        let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+        let res = listener.set_nonblocking(true);
+        let fd = listener.into_raw_fd();
+        res?;
+        let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+        if let Err(err) = poll.register(&tcp, ALL, Ready::readable(), PollOpt::level()) {
+            let _ = tcp.into_raw_fd();
+            return Err(Error::from(err))
+        }
+        self.listener = Some(tcp);
+        self.accepting = true;
+        Ok(self)
+    }
+
+    pub fn listen_tcp(&mut self, poll: &mut Poll, listener: net::TcpListener) -> Result<&mut Handler<F>> {
+        debug_assert!(self.listener.is_none(), "Attempted to listen for connections from two sockets on the same websocket.");
+
+        // from_std puts the listener into non-blocking mode, which the event loop relies on
+        let tcp = TcpListener::from_std(listener)?;
+        self.adopt(poll, tcp)
+    }
+
+    fn adopt(&mut self, poll: &mut Poll, tcp: TcpListener) -> Result<&mut Handler<F>> {
         poll.register(&tcp, ALL, Ready::readable(), PollOpt::level())?;
         self.listener = Some(tcp);
+        self.accepting = true;
         Ok(self)
     }
     
@@ -139,20 +374,39 @@ impl<F> Handler<F>
     }
     
     
-    pub fn connect(&mut self, poll: &mut Poll, url: String) -> Result<()> {
+    pub fn connect(&mut self, poll: &mut Poll, url: String, slot: OutgoingSlot) -> Result<()> {
+        self.connect_to(poll, url, None, slot)
+    }
+    
+    pub fn connect_addr(&mut self, poll: &mut Poll, addr: SocketAddr, slot: OutgoingSlot) -> Result<()> {
+        self.connect_to(poll, addr.to_string(), Some(vec![addr]), slot)
+    }
+    
+    // Resolves the url unless the addresses are given. The slot is released along with the
+    // connection, or right away if it can't be made.
+    fn connect_to(&mut self, poll: &mut Poll, url: String, addresses: Option<Vec<SocketAddr>>, slot: OutgoingSlot)
+                  -> Result<()>
+    {
         let settings = self.settings;
         
+        self.reserve_connection();
         let (tok, addresses) = {
             let (tok, entry, connection_id, handler) = if let Some(entry) = self.connections.vacant_entry() {
                 let tok = entry.index();
                 let connection_id = self.next_connection_id;
                 self.next_connection_id = self.next_connection_id.wrapping_add(1);
-                (tok, entry, connection_id, self.factory.client_connected(Sender::new(tok, self.queue_tx.clone(), connection_id)))
+                let sender = Sender::new(tok, self.queue_tx.clone(), connection_id)
+                    .client()
+                    .outgoing(self.outgoing.clone());
+                let handler = build_handler(&mut self.factory, settings.catch_handler_panics, |factory| {
+                    factory.client_connected(sender)
+                })?;
+                (tok, entry, connection_id, handler)
             } else {
                 return Err(Error::new(Kind::Capacity, "Unable to add another connection to the event loop."));
             };
             
-            let mut addresses = match url_to_addrs(&url) {
+            let mut addresses = match addresses.map_or_else(|| url_to_addrs(&url), Ok) {
                 Ok(addresses) => addresses,
                 Err(err) => {
                     self.factory.connection_lost(handler);
@@ -166,10 +420,12 @@ impl<F> Handler<F>
                         if settings.tcp_nodelay {
                             sock.set_nodelay(true)?
                         }
-                        let mut conn = Connection::new(tok, sock, handler, settings, connection_id);
-                        conn.open();
+                        set_buffer_sizes(&sock, &settings);
+                        let mut handler = handler;
+                        handler.on_connecting(addr);
+                        let mut conn = Connection::new(tok, sock, handler, settings, connection_id, self.budget.clone());
+                        conn.hold_outgoing(slot);
                         entry.insert(conn);
-                        //TODO connected to do on_open() function
                         break
                     }
                 } else {
@@ -190,6 +446,7 @@ impl<F> Handler<F>
             return Err(error);
         }
         
+        // the connection is opened once the connect completes
         poll.register(
             self.connections[tok].socket(),
             self.connections[tok].token(),
@@ -205,29 +462,53 @@ impl<F> Handler<F>
     
     
     pub fn accept(&mut self, poll: &mut Poll, sock: TcpStream) -> Result<()> {
-        let factory = &mut self.factory;
         let settings = self.settings;
         
         if settings.tcp_nodelay {
             sock.set_nodelay(true)?
         }
+        set_buffer_sizes(&sock, &settings);
+        
+        self.reserve_connection();
+        let factory = &mut self.factory;
+        let budget = self.budget.clone();
         
         let tok = {
             if let Some(entry) = self.connections.vacant_entry() {
                 let tok = entry.index();
                 let connection_id = self.next_connection_id;
                 self.next_connection_id = self.next_connection_id.wrapping_add(1);
-                let handler = factory.server_connected(Sender::new(tok, self.queue_tx.clone(), connection_id));
-                entry.insert(Connection::new(tok, sock, handler, settings, connection_id));
+                let sender = Sender::new(tok, self.queue_tx.clone(), connection_id).outgoing(self.outgoing.clone());
+                let handler = build_handler(factory, settings.catch_handler_panics, |factory| {
+                    factory.server_connected(sender)
+                })?;
+                entry.insert(Connection::new(tok, sock, handler, settings, connection_id, budget));
                 tok
             } else {
                 return Err(Error::new(Kind::Capacity, "Unable to add another connection to the event loop."));
             }
         };
         
-        let conn = &mut self.connections[tok];
+        {
+            let conn = &mut self.connections[tok];
+            
+            conn.as_server()?;//监听可读
+            
+            //open connection on_open() to change state
+            trace!("acecept new connection");
+            if let Err(err) = conn.open() {
+                conn.error(err);
+            }
+        }
         
-        conn.as_server()?;//监听可读
+        if self.connections[tok].events().is_empty() {
+            // the handler refused the connection in on_open
+            let handler = self.connections.remove(tok).unwrap().consume();
+            self.factory.connection_lost(handler);
+            return Ok(());
+        }
+        
+        let conn = &mut self.connections[tok];
         
         let ret: Result<()> = poll.register(
             conn.socket(),
@@ -244,18 +525,7 @@ impl<F> Handler<F>
             }
             Ok(())
         });
-        ret?;
-        
-        //open connection on_open() to change state
-        trace!("acecept new connection");
-        conn.open().map_err(Error::from).or_else(|err| {
-            error!("Encountered error while trying to build socket connection: {}", err);
-            conn.error(err);
-            if settings.panic_on_new_connection {
-                panic!("Encountered error while trying to build socket connection.");
-            }
-            Ok(())
-        })
+        ret
     }
     
     pub fn run(&mut self, poll: &mut Poll) -> Result<()> {
@@ -264,7 +534,12 @@ impl<F> Handler<F>
         poll.register(&self.timer, TIMER, Ready::readable(), PollOpt::edge())?;
         
         self.state = State::Active;
+        self.shutting_down = false;
+        let stdout_fallback = set_stdout_fallback(self.settings.error_stdout_fallback);
+        self.factory.on_loop_start();
         let result = self.event_loop(poll);
+        self.factory.on_loop_stop();
+        set_stdout_fallback(stdout_fallback);
         self.state = State::Inactive;
         
         result
@@ -275,9 +550,16 @@ impl<F> Handler<F>
     #[inline]
     fn event_loop(&mut self, poll: &mut Poll) -> Result<()> {
         let mut events = mio::Events::with_capacity(MAX_EVENTS);
+        let max_poll_timeout = self.settings.max_poll_timeout_ms.map(Duration::from_millis);
         while self.state.is_active() {
             trace!("Waiting for event");
-            let nevents = match poll.poll(&mut events, None) {
+            // don't block while a broadcast is still being buffered
+            let poll_timeout = if self.broadcasts.is_empty() {
+                max_poll_timeout
+            } else {
+                Some(Duration::from_millis(0))
+            };
+            let nevents = match poll.poll(&mut events, poll_timeout) {
                 //监听接收事件。
                 Ok(nevents) => nevents,
                 Err(err) => {
@@ -295,27 +577,171 @@ impl<F> Handler<F>
                 }
             };
             trace!("Processing {} events", nevents);
+            let started = Instant::now();
+            self.iterations += 1;
+            self.removed.clear();
             
-            for i in 0..nevents {
-                let evt = events.get(i).unwrap();
+            // queued commands go first, so they don't wait behind socket io
+            for evt in events.iter().filter(|evt| evt.token() == QUEUE) {
+                self.handle_event(poll, evt.token(), evt.kind());
+            }
+            for evt in events.iter().filter(|evt| evt.token() != QUEUE) {
                 self.handle_event(poll, evt.token(), evt.kind());
             }
             
+            if nevents == 0 {
+                // idle wakeup, make sure no due timeout is left waiting
+                while let Some(t) = self.timer.poll() {
+                    self.handle_timeout(poll, t);
+                }
+            }
+            
+            if !self.broadcasts.is_empty() {
+                let batch = self.settings.broadcast_batch_size;
+                self.continue_broadcasts(poll, batch);
+            }
+            
             self.check_count();
+            self.update_accept(poll);
+            self.last_poll_duration = started.elapsed();
         }
         Ok(())
     }
     
+    fn broadcast_shared(&mut self, poll: &mut Poll, data: Arc<Vec<u8>>) {
+        if !self.broadcasts.is_empty() || self.connections.len() > self.settings.broadcast_batch_size {
+            trace!("Broadcasting to {} connections in batches.", self.connections.len());
+            let remaining = self.connections.iter().map(|conn| (conn.token(), conn.connection_id())).collect();
+            self.broadcasts.push_back(PendingBroadcast {
+                data: data,
+                remaining: remaining,
+            });
+            let batch = self.settings.broadcast_batch_size;
+            return self.continue_broadcasts(poll, batch);
+        }
+        let mut dead = Vec::new();
+        for conn in self.connections.iter_mut() {
+            if let Err(err) = conn.send_shared(data.clone()) {
+                dead.push((conn.token(), err))
+            }
+        }
+        self.finish_broadcast(poll, dead)
+    }
+    
+    fn finish_broadcast(&mut self, poll: &mut Poll, dead: Vec<(Token, Error)>) {
+        for (token, err) in dead {
+            self.connections[token].error(err)
+        }
+        // reschedule every connection, dropping the ones that are done
+        let tokens: Vec<Token> = self.connections.iter().map(|conn| conn.token()).collect();
+        for token in tokens {
+            let active = !self.connections[token].events().is_empty();
+            self.check_active(poll, active, token);
+        }
+    }
+    
+    // Buffer pending broadcasts on up to `budget` connections, oldest broadcast first.
+    fn continue_broadcasts(&mut self, poll: &mut Poll, budget: usize) {
+        let mut budget = budget;
+        let mut touched = Vec::new();
+        while budget > 0 {
+            let done = if let Some(broadcast) = self.broadcasts.front_mut() {
+                while budget > 0 {
+                    if let Some((token, connection_id)) = broadcast.remaining.pop() {
+                        budget -= 1;
+                        if let Some(conn) = self.connections.get_mut(token) {
+                            if conn.connection_id() == connection_id {
+                                if let Err(err) = conn.send_shared(broadcast.data.clone()) {
+                                    conn.error(err)
+                                }
+                                touched.push(token);
+                            }
+                        }
+                    } else {
+                        break
+                    }
+                }
+                broadcast.remaining.is_empty()
+            } else {
+                break
+            };
+            if done {
+                self.broadcasts.pop_front();
+            }
+        }
+        touched.sort();
+        touched.dedup();
+        for token in touched {
+            let active = !self.connections[token].events().is_empty();
+            self.check_active(poll, active, token);
+        }
+    }
+    
+    // Buffer any pending broadcasts on this connection now, so that nothing sent to it later
+    // overtakes them.
+    fn catch_up_broadcasts(&mut self, token: Token, connection_id: u32) {
+        if self.broadcasts.is_empty() {
+            return
+        }
+        if let Some(conn) = self.connections.get_mut(token) {
+            if conn.connection_id() != connection_id {
+                return
+            }
+            for broadcast in self.broadcasts.iter_mut() {
+                let owed = broadcast.remaining.iter().position(|&entry| entry == (token, connection_id));
+                if let Some(index) = owed {
+                    broadcast.remaining.swap_remove(index);
+                    if let Err(err) = conn.send_shared(broadcast.data.clone()) {
+                        conn.error(err)
+                    }
+                }
+            }
+        }
+        self.broadcasts.retain(|broadcast| !broadcast.remaining.is_empty());
+    }
+    
     #[inline]
+    fn diagnostics(&self) -> Diagnostics {
+        let mut diagnostics = Diagnostics {
+            connections: self.connections.len(),
+            connecting: 0,
+            open: 0,
+            closing: 0,
+            queued_bytes: 0,
+            pending_timeouts: self.pending_timeouts,
+            iterations: self.iterations,
+            last_poll_duration: self.last_poll_duration,
+        };
+        for conn in self.connections.iter() {
+            if conn.is_connecting() {
+                diagnostics.connecting += 1
+            } else if conn.is_open() {
+                diagnostics.open += 1
+            } else {
+                diagnostics.closing += 1
+            }
+            diagnostics.queued_bytes += conn.queued_bytes();
+        }
+        diagnostics
+    }
+    
     fn schedule(&self, poll: &mut Poll, conn: &Conn<F>) -> Result<()> {
         trace!("Scheduling connection to {} as {:?}", conn.socket().peer_addr().map(|addr| addr.to_string()).unwrap_or("UNKNOWN".into()), conn.events());
         Ok(poll.reregister(conn.socket(), conn.token(), conn.events(), PollOpt::edge() | PollOpt::oneshot())?)
     }
     
     fn shutdown(&mut self) {
+        if self.shutting_down {
+            debug!("Ignoring repeated shutdown signal.");
+            return;
+        }
+        self.shutting_down = true;
         debug!("Received shutdown signal. socket is attempting to shut down.");
-        for conn in self.connections.iter_mut() {
-            conn.shutdown();
+        // tear connections down in ascending token order, so handlers are called predictably
+        let mut tokens: Vec<Token> = self.connections.iter().map(|conn| conn.token()).collect();
+        tokens.sort();
+        for token in tokens {
+            self.connections[token].shutdown();
         }
         self.factory.on_shutdown();
         self.state = State::Inactive;
@@ -335,19 +761,66 @@ impl<F> Handler<F>
             } else {
                 trace!("socket connection to token={:?} disconnected.", token);
             }
-            let handler = self.connections.remove(token).unwrap().consume();
-            self.factory.connection_lost(handler);
+            self.remove_connection(token);
         } else {
             self.schedule(poll, &self.connections[token]).or_else(|err| {
                 // This will be an io error, so disconnect will already be called
                 self.connections[token].error(Error::from(err));
-                let handler = self.connections.remove(token).unwrap().consume();
-                self.factory.connection_lost(handler);
+                self.remove_connection(token);
                 Ok::<(), Error>(())
             }).unwrap()
         }
     }
     
+    // Send a message to the connections tagged with `label` and tell how many there were.
+    fn send_to_label(&mut self, poll: &mut Poll, label: &str, msg: Message) -> Result<usize> {
+        let tokens = match self.labels.get(label) {
+            Some(tokens) => tokens.clone(),
+            None => return Err(Error::new(Kind::NotFound, format!("No connection is labeled {:?}.", label))),
+        };
+        if tokens.len() > 1 && self.settings.duplicate_labels == DuplicateLabels::Reject {
+            return Err(Error::new(
+                Kind::Internal,
+                format!("Not sending to label {:?}, which is set on {} connections.", label, tokens.len())))
+        }
+        for &token in tokens.iter() {
+            let connection_id = self.connections[token].connection_id();
+            self.catch_up_broadcasts(token, connection_id);
+            let conn = &mut self.connections[token];
+            if let Err(err) = conn.send_message(msg.clone()) {
+                conn.error(err)
+            }
+        }
+        for &token in tokens.iter() {
+            let active = {
+                let conn = &self.connections[token];
+                conn.events().is_readable() || conn.events().is_writable()
+            };
+            self.check_active(poll, active, token)
+        }
+        Ok(tokens.len())
+    }
+    
+    // Drop a connection that is done, along with the timeouts it still has scheduled.
+    fn remove_connection(&mut self, token: Token) {
+        let mut conn = self.connections.remove(token).unwrap();
+        self.removed.push(token);
+        if let Some(label) = conn.log_label() {
+            unlabel(&mut self.labels, label, token);
+        }
+        if let Some(timeouts) = self.timeouts.remove(&token) {
+            for (_, timeout) in timeouts {
+                if let Some(Timeout { event, deadline, .. }) = self.timer.cancel_timeout(&timeout) {
+                    self.pending_timeouts -= 1;
+                    if deadline.is_none() {
+                        conn.timeout_dropped(event)
+                    }
+                }
+            }
+        }
+        self.factory.connection_lost(conn.consume());
+    }
+    
     #[inline]
     fn is_client(&self) -> bool {
         self.listener.is_none()
@@ -388,6 +861,11 @@ impl<F> Handler<F>
                                     }
                                 }
                             }
+                            Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
+                            Err(ref err) if is_transient_accept_error(err) => {
+                                warn!("Unable to accept tcp connection ({}), retrying in {}ms.", err, ACCEPT_BACKOFF_MILLIS);
+                                self.backoff_accept(poll);
+                            }
                             Err(err) => error!("Encountered an error {:?} while accepting tcp connection.", err),
                         }
                 }
@@ -399,7 +877,8 @@ impl<F> Handler<F>
             }
             QUEUE => {
                 //监听的队列事件发生，接受服务的发的数据，服务发的数据都是通过chanel一起发的。
-                for _ in 0..MESSAGES_PER_TICK {
+                // commands are handled one by one in the order they were queued, see Sender
+                for _ in 0..self.settings.max_commands_per_poll {
                     match self.queue_rx.try_recv() {
                         Ok(cmd) => self.handle_queue(poll, cmd),
                         _ => break
@@ -409,6 +888,12 @@ impl<F> Handler<F>
             }
             _ => {
                 //监听的socket事件发生。
+                if self.connections.get(token).is_none() || self.removed.contains(&token) {
+                    // a command handled earlier in this iteration dropped the connection, and
+                    // another one may have taken its token, so the event is for a closed socket
+                    trace!("Ignoring event for disconnected token={:?}.", token);
+                    return;
+                }
                 let active = {
                     let conn_events = self.connections[token].events();
                     if (events & conn_events).is_readable() {
@@ -428,8 +913,7 @@ impl<F> Handler<F>
                                                     PollOpt::edge() | PollOpt::oneshot(),
                                                 ).or_else(|err| {
                                                     self.connections[token].error(Error::from(err));
-                                                    let handler = self.connections.remove(token).unwrap().consume();
-                                                    self.factory.connection_lost(handler);
+                                                    self.remove_connection(token);
                                                     Ok::<(), Error>(())
                                                 }).unwrap();
                                                 return;
@@ -465,8 +949,7 @@ impl<F> Handler<F>
                                                     PollOpt::edge() | PollOpt::oneshot(),
                                                 ).or_else(|err| {
                                                     self.connections[token].error(Error::from(err));
-                                                    let handler = self.connections.remove(token).unwrap().consume();
-                                                    self.factory.connection_lost(handler);
+                                                    self.remove_connection(token);
                                                     Ok::<(), Error>(())
                                                 }).unwrap();
                                                 return;
@@ -500,16 +983,74 @@ impl<F> Handler<F>
             ALL => {
                 //broadcasting message with type
                 let mut dead = Vec::with_capacity(self.connections.len());
+                let signal = cmd.signal();
                 
-                match cmd.signal() {
+                if let Signal::Message(_) = signal {
+                    // queues up behind pending broadcasts as needed
+                } else if let Signal::Shared(_) = signal {
+                    // same as a message
+                } else if let Signal::Diagnostics(_) = signal {
+                    // reports pending broadcasts as they are
+                } else if !self.broadcasts.is_empty() {
+                    // everything else has to wait until pending broadcasts are done
+                    self.continue_broadcasts(poll, usize::MAX);
+                }
+                
+                match signal {
                     Signal::Message(msg) => {
                         trace!("Broadcasting message: {:?}", msg);
+                        // every connection writes from the same copy of the data
+                        return self.broadcast_shared(poll, Arc::new(msg.into_data()));
+                    }
+                    Signal::Shared(data) => {
+                        trace!("Broadcasting {} prepared bytes", data.len());
+                        return self.broadcast_shared(poll, data);
+                    }
+                    Signal::Messages(msgs) => {
+                        trace!("Broadcasting {} messages", msgs.len());
+                        let batch: Vec<_> = msgs.into_iter().map(|msg| Arc::new(msg.into_data())).collect();
+                        for conn in self.connections.iter_mut() {
+                            for data in batch.iter() {
+                                if let Err(err) = conn.send_shared(data.clone()) {
+                                    dead.push((conn.token(), err));
+                                    break
+                                }
+                            }
+                        }
+                    }
+                    Signal::MessageWithNotify(msg, event) => {
+                        trace!("Broadcasting message with notification: {:?}", msg);
+                        for conn in self.connections.iter_mut() {
+                            if let Err(err) = conn.send_message_with_notify(msg.clone(), event) {
+                                dead.push((conn.token(), err))
+                            }
+                        }
+                    }
+                    Signal::MessageIfOpen(msg, reply) => {
+                        trace!("Broadcasting message to open connections: {:?}", msg);
+                        let mut buffered = false;
                         for conn in self.connections.iter_mut() {
-                            if let Err(err) = conn.send_message(msg.clone()) {
+                            match conn.send_message_if_open(msg.clone()) {
+                                Ok(sent) => buffered = buffered || sent,
+                                Err(err) => dead.push((conn.token(), err)),
+                            }
+                        }
+                        let _ = reply.send(buffered);
+                    }
+                    Signal::MessageWithDeadline(msg, deadline) => {
+                        trace!("Broadcasting message with deadline: {:?}", msg);
+                        for conn in self.connections.iter_mut() {
+                            if let Err(err) = conn.send_message_with_deadline(msg.clone(), deadline) {
                                 dead.push((conn.token(), err))
                             }
                         }
                     }
+                    Signal::Abort => {
+                        trace!("Aborting all connections");
+                        for conn in self.connections.iter_mut() {
+                            conn.abort()
+                        }
+                    }
                     Signal::Close(code, reason) => {
                         trace!("Broadcasting close: {:?} - {}", code, reason);
                         for conn in self.connections.iter_mut() {
@@ -519,8 +1060,8 @@ impl<F> Handler<F>
                         }
                     }
                     
-                    Signal::Connect(url) => {
-                        if let Err(err) = self.connect(poll, url.clone()) {
+                    Signal::Connect(url, slot) => {
+                        if let Err(err) = self.connect(poll, url.clone(), slot) {
                             if self.settings.panic_on_new_connection {
                                 panic!("Unable to establish connection to {}: {:?}", url, err);
                             }
@@ -528,13 +1069,28 @@ impl<F> Handler<F>
                         }
                         return;
                     }
+                    Signal::ConnectAddr(addr, slot) => {
+                        if let Err(err) = self.connect_addr(poll, addr, slot) {
+                            if self.settings.panic_on_new_connection {
+                                panic!("Unable to establish connection to {}: {:?}", addr, err);
+                            }
+                            error!("Unable to establish connection to {}: {:?}", addr, err);
+                        }
+                        return;
+                    }
                     Signal::Shutdown => self.shutdown(),
+                    Signal::StopAccepting => {
+                        debug!("Stopping accepting new connections.");
+                        self.accept_stopped = true;
+                        return self.update_accept(poll);
+                    }
+                    Signal::ResumeAccepting => {
+                        debug!("Resuming accepting new connections.");
+                        self.accept_stopped = false;
+                        return self.update_accept(poll);
+                    }
                     Signal::Timeout { delay, token: event } => {
-                        match self.timer.set_timeout(Duration::from_millis(delay),
-                                                     Timeout {
-                                                         connection: ALL,
-                                                         event: event,
-                                                     }).map_err(Error::from)
+                        match self.set_timeout(Duration::from_millis(delay), ALL, event, None)
                             {
                                 Ok(timeout) => {
                                     for conn in self.connections.iter_mut() {
@@ -553,26 +1109,61 @@ impl<F> Handler<F>
                         return;
                     }
                     Signal::Cancel(timeout) => {
-                        self.timer.cancel_timeout(&timeout);
+                        self.cancel_timeout(&timeout);
                         return;
                     }
-                }
-                
-                for conn in self.connections.iter() {
-                    if let Err(err) = self.schedule(poll, conn) {
-                        dead.push((conn.token(), err))
+                    Signal::CancelAll => {
+                        self.cancel_all_timeouts(ALL);
+                        return;
+                    }
+                    Signal::ReadTimeout(delay) => {
+                        let tokens: Vec<_> = self.connections.iter().map(|conn| (conn.token(), conn.read_deadline())).collect();
+                        for (token, deadline) in tokens {
+                            self.set_deadline(delay, token, deadline)
+                        }
+                        return;
+                    }
+                    Signal::WriteTimeout(delay) => {
+                        let tokens: Vec<_> = self.connections.iter().map(|conn| conn.token()).collect();
+                        for token in tokens {
+                            self.set_deadline(delay, token, Deadline::Write)
+                        }
+                        return;
+                    }
+                    Signal::Diagnostics(reply) => {
+                        // the requester may have given up waiting
+                        let _ = reply.send(self.diagnostics());
+                        return;
+                    }
+                    Signal::QueuedBytes(reply) => {
+                        let queued = self.connections.iter().map(|conn| conn.queued_bytes()).sum();
+                        let _ = reply.send(Ok(queued));
+                        return;
+                    }
+                    Signal::LogLabel(label) => {
+                        trace!("Ignoring log label {:?} sent on the broadcaster.", label);
+                        return;
+                    }
+                    Signal::LabeledMessage(label, msg, reply) => {
+                        let _ = reply.send(self.send_to_label(poll, &label, msg));
+                        return;
                     }
                 }
-                for (token, err) in dead {
-                    // note the same connection may be called twice
-                    self.connections[token].error(err)
-                }
+                
+                self.finish_broadcast(poll, dead)
             }
             
             token => {
                 //single socket send message
                 let connection_id = cmd.connection_id();
-                match cmd.signal() {
+                let signal = cmd.signal();
+                match signal {
+                    Signal::Connect(..) | Signal::ConnectAddr(..) | Signal::Shutdown | Signal::StopAccepting |
+                    Signal::ResumeAccepting | Signal::Timeout { .. } | Signal::Cancel(_) |
+                    Signal::CancelAll | Signal::Diagnostics(_) | Signal::LabeledMessage(..) => (),
+                    _ => self.catch_up_broadcasts(token, connection_id),
+                }
+                match signal {
                     Signal::Message(msg) => {
                         if let Some(conn) = self.connections.get_mut(token) {
                             if conn.connection_id() == connection_id {
@@ -586,6 +1177,82 @@ impl<F> Handler<F>
                             trace!("Connection disconnected while a message was waiting in the queue.")
                         }
                     }
+                    Signal::Messages(msgs) => {
+                        if let Some(conn) = self.connections.get_mut(token) {
+                            if conn.connection_id() == connection_id {
+                                if let Err(err) = conn.send_messages(msgs) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while messages were waiting in the queue.")
+                            }
+                        } else {
+                            trace!("Connection disconnected while messages were waiting in the queue.")
+                        }
+                    }
+                    Signal::Shared(data) => {
+                        if let Some(conn) = self.connections.get_mut(token) {
+                            if conn.connection_id() == connection_id {
+                                if let Err(err) = conn.send_shared(data) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while a message was waiting in the queue.")
+                            }
+                        } else {
+                            trace!("Connection disconnected while a message was waiting in the queue.")
+                        }
+                    }
+                    Signal::MessageWithNotify(msg, event) => {
+                        if let Some(conn) = self.connections.get_mut(token) {
+                            if conn.connection_id() == connection_id {
+                                if let Err(err) = conn.send_message_with_notify(msg, event) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while a message was waiting in the queue.")
+                            }
+                        } else {
+                            trace!("Connection disconnected while a message was waiting in the queue.")
+                        }
+                    }
+                    Signal::MessageIfOpen(msg, reply) => {
+                        let mut buffered = false;
+                        match self.connections.get_mut(token) {
+                            Some(conn) if conn.connection_id() == connection_id => {
+                                match conn.send_message_if_open(msg) {
+                                    Ok(sent) => buffered = sent,
+                                    Err(err) => conn.error(err),
+                                }
+                            }
+                            _ => trace!("Connection disconnected while a message was waiting in the queue."),
+                        }
+                        let _ = reply.send(buffered);
+                    }
+                    Signal::MessageWithDeadline(msg, deadline) => {
+                        if let Some(conn) = self.connections.get_mut(token) {
+                            if conn.connection_id() == connection_id {
+                                if let Err(err) = conn.send_message_with_deadline(msg, deadline) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while a message was waiting in the queue.")
+                            }
+                        } else {
+                            trace!("Connection disconnected while a message was waiting in the queue.")
+                        }
+                    }
+                    Signal::Abort => {
+                        if let Some(conn) = self.connections.get_mut(token) {
+                            if conn.connection_id() == connection_id {
+                                conn.abort()
+                            } else {
+                                trace!("Connection disconnected while abort signal was waiting in the queue.")
+                            }
+                        } else {
+                            trace!("Connection disconnected while abort signal was waiting in the queue.")
+                        }
+                    }
                     Signal::Close(code, reason) => {
                         if let Some(conn) = self.connections.get_mut(token) {
                             if conn.connection_id() == connection_id {
@@ -600,8 +1267,8 @@ impl<F> Handler<F>
                         }
                     }
                     
-                    Signal::Connect(url) => {
-                        if let Err(err) = self.connect(poll, url.clone()) {
+                    Signal::Connect(url, slot) => {
+                        if let Err(err) = self.connect(poll, url.clone(), slot) {
                             if let Some(conn) = self.connections.get_mut(token) {
                                 conn.error(err)
                             } else {
@@ -613,13 +1280,32 @@ impl<F> Handler<F>
                         }
                         return;
                     }
+                    Signal::ConnectAddr(addr, slot) => {
+                        if let Err(err) = self.connect_addr(poll, addr, slot) {
+                            if let Some(conn) = self.connections.get_mut(token) {
+                                conn.error(err)
+                            } else {
+                                if self.settings.panic_on_new_connection {
+                                    panic!("Unable to establish connection to {}: {:?}", addr, err);
+                                }
+                                error!("Unable to establish connection to {}: {:?}", addr, err);
+                            }
+                        }
+                        return;
+                    }
                     Signal::Shutdown => self.shutdown(),
+                    Signal::StopAccepting => {
+                        debug!("Stopping accepting new connections.");
+                        self.accept_stopped = true;
+                        return self.update_accept(poll);
+                    }
+                    Signal::ResumeAccepting => {
+                        debug!("Resuming accepting new connections.");
+                        self.accept_stopped = false;
+                        return self.update_accept(poll);
+                    }
                     Signal::Timeout { delay, token: event } => {
-                        match self.timer.set_timeout(Duration::from_millis(delay),
-                                                     Timeout {
-                                                         connection: token,
-                                                         event: event,
-                                                     }).map_err(Error::from)
+                        match self.set_timeout(Duration::from_millis(delay), token, event, None)
                             {
                                 Ok(timeout) => {
                                     if let Some(conn) = self.connections.get_mut(token) {
@@ -641,25 +1327,208 @@ impl<F> Handler<F>
                         return;
                     }
                     Signal::Cancel(timeout) => {
-                        self.timer.cancel_timeout(&timeout);
+                        self.cancel_timeout(&timeout);
+                        return;
+                    }
+                    Signal::CancelAll => {
+                        match self.connections.get(token) {
+                            Some(conn) if conn.connection_id() == connection_id => self.cancel_all_timeouts(token),
+                            _ => trace!("Connection disconnected while cancel signal was waiting in the queue."),
+                        }
+                        return;
+                    }
+                    Signal::ReadTimeout(delay) | Signal::WriteTimeout(delay) => {
+                        let deadline = match self.connections.get(token) {
+                            Some(conn) if conn.connection_id() == connection_id => {
+                                if let Signal::ReadTimeout(_) = signal {
+                                    Some(conn.read_deadline())
+                                } else {
+                                    Some(Deadline::Write)
+                                }
+                            }
+                            _ => None,
+                        };
+                        if let Some(deadline) = deadline {
+                            self.set_deadline(delay, token, deadline)
+                        } else {
+                            trace!("Connection disconnected while timeout signal was waiting in the queue.")
+                        }
+                    }
+                    Signal::Diagnostics(reply) => {
+                        // the requester may have given up waiting
+                        let _ = reply.send(self.diagnostics());
+                        return;
+                    }
+                    Signal::QueuedBytes(reply) => {
+                        match self.connections.get(token) {
+                            Some(conn) if conn.connection_id() == connection_id => {
+                                let _ = reply.send(Ok(conn.queued_bytes()));
+                            }
+                            _ => {
+                                trace!("Connection disconnected while queued bytes signal was waiting in the queue.");
+                                let _ = reply.send(Err(Error::new(Kind::NotFound, "The connection is gone.")));
+                            }
+                        }
+                        return;
+                    }
+                    Signal::LogLabel(label) => {
+                        match self.connections.get_mut(token) {
+                            Some(conn) if conn.connection_id() == connection_id => {
+                                if let Some(old) = conn.log_label() {
+                                    unlabel(&mut self.labels, old, token);
+                                }
+                                self.labels.entry(label.clone()).or_default().push(token);
+                                conn.set_log_label(label)
+                            }
+                            _ => trace!("Connection disconnected while log label signal was waiting in the queue."),
+                        }
+                        return;
+                    }
+                    Signal::LabeledMessage(label, msg, reply) => {
+                        let _ = reply.send(self.send_to_label(poll, &label, msg));
                         return;
                     }
                 }
                 
-                if let Some(_) = self.connections.get(token) {
-                    if let Err(err) = self.schedule(poll, &self.connections[token]) {
-                        self.connections[token].error(err)
-                    }
+                // the command may have caused the connection to disconnect
+                let active = if let Some(conn) = self.connections.get(token) {
+                    conn.events().is_readable() || conn.events().is_writable()
+                } else {
+                    return;
+                };
+                self.check_active(poll, active, token)
+            }
+        }
+    }
+    
+    
+    // The listener is level triggered, so while accept keeps failing we would spin. Stop
+    // listening for a moment and let the timer pick it back up.
+    fn backoff_accept(&mut self, poll: &mut Poll) {
+        self.accept_backoff = true;
+        self.update_accept(poll);
+        if let Err(err) = self.set_timeout(Duration::from_millis(ACCEPT_BACKOFF_MILLIS), SYSTEM, ALL, None) {
+            error!("Unable to schedule accept retry: {:?}", err);
+            self.resume_accept(poll);
+        }
+    }
+    
+    fn resume_accept(&mut self, poll: &mut Poll) {
+        self.accept_backoff = false;
+        self.update_accept(poll);
+    }
+    
+    // Register or deregister the listener depending on whether new connections should be
+    // accepted right now. Under `AcceptPolicy::Backpressure` a full server leaves new
+    // connections waiting in the listen backlog until a slot frees up.
+    fn update_accept(&mut self, poll: &mut Poll) {
+        let full = self.settings.accept_policy == AcceptPolicy::Backpressure &&
+            self.connections.len() >= self.settings.max_connections;
+        let accept = !self.accept_backoff && !self.accept_stopped && !full;
+        if accept == self.accepting {
+            return;
+        }
+        if let Some(ref listener) = self.listener {
+            if accept {
+                trace!("Accepting new connections.");
+                if let Err(err) = poll.register(listener, ALL, Ready::readable(), PollOpt::level()) {
+                    error!("Unable to resume accepting connections: {}", err);
+                    return;
+                }
+            } else {
+                trace!("Pausing accepting new connections.");
+                if let Err(err) = poll.deregister(listener) {
+                    error!("Unable to pause accepting connections: {}", err);
+                    return;
                 }
             }
+            self.accepting = accept;
         }
     }
     
+    fn set_timeout(&mut self, delay: Duration, connection: Token, event: Token, deadline: Option<(u32, Deadline)>)
+                   -> Result<mio::timer::Timeout>
+    {
+        // the timer grows past its capacity instead of refusing, so the limit is kept here
+        if self.pending_timeouts >= self.settings.timer_capacity {
+            return Err(Error::new(
+                Kind::Timer(mio::timer::TimerError),
+                format!("Unable to schedule more than {} timeouts.", self.settings.timer_capacity)))
+        }
+        let id = self.next_timeout_id;
+        let timeout = self.timer.set_timeout(delay, Timeout {
+            connection: connection,
+            event: event,
+            id: id,
+            deadline: deadline,
+        })?;
+        self.next_timeout_id += 1;
+        self.pending_timeouts += 1;
+        self.timeouts.entry(connection).or_default().push((id, timeout.clone()));
+        Ok(timeout)
+    }
     
-    fn handle_timeout(&mut self, poll: &mut Poll, Timeout { connection, event }: Timeout) {
+    fn set_deadline(&mut self, delay: Duration, token: Token, deadline: Deadline) {
+        let connection_id = self.connections[token].connection_id();
+        if let Err(err) = self.set_timeout(delay, token, ALL, Some((connection_id, deadline))) {
+            self.connections[token].error(err)
+        }
+    }
+    
+    fn cancel_timeout(&mut self, timeout: &mio::timer::Timeout) {
+        if let Some(Timeout { connection, id, .. }) = self.timer.cancel_timeout(timeout) {
+            self.forget_timeout(connection, id);
+        }
+    }
+    
+    fn cancel_all_timeouts(&mut self, connection: Token) {
+        if let Some(timeouts) = self.timeouts.remove(&connection) {
+            trace!("Cancelling {} timeouts of {:?}", timeouts.len(), connection);
+            for (_, timeout) in timeouts {
+                if self.timer.cancel_timeout(&timeout).is_some() {
+                    self.pending_timeouts -= 1;
+                }
+            }
+        }
+    }
+    
+    // The timeout has fired or was cancelled.
+    fn forget_timeout(&mut self, connection: Token, id: u64) {
+        self.pending_timeouts -= 1;
+        let empty = match self.timeouts.get_mut(&connection) {
+            Some(timeouts) => {
+                timeouts.retain(|&(other, _)| other != id);
+                timeouts.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.timeouts.remove(&connection);
+        }
+    }
+    
+    fn handle_timeout(&mut self, poll: &mut Poll, Timeout { connection, event, id, deadline }: Timeout) {
+        self.forget_timeout(connection, id);
+        if connection == SYSTEM {
+            if event == ALL {
+                trace!("Resuming accepting connections.");
+                self.resume_accept(poll);
+            }
+            return;
+        }
         let active = {
             if let Some(conn) = self.connections.get_mut(connection) {
-                if let Err(err) = conn.timeout_triggered(event) {
+                let res = match deadline {
+                    Some((connection_id, deadline)) => {
+                        if conn.connection_id() != connection_id {
+                            trace!("Connection disconnected while deadline was waiting.");
+                            return;
+                        }
+                        conn.deadline_passed(deadline)
+                    }
+                    None => conn.timeout_triggered(event),
+                };
+                if let Err(err) = res {
                     conn.error(err)
                 }
                 
@@ -674,3 +1543,106 @@ impl<F> Handler<F>
 }
 
 
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+    use handler;
+
+    struct H;
+
+    impl handler::Handler for H {}
+
+    #[test]
+    fn presized_connections() {
+        let settings = Settings {
+            max_connections: 1000,
+            initial_connection_capacity: 1000,
+            ..Settings::default()
+        };
+        let handler = Handler::new(|_| H, settings);
+        assert_eq!(handler.connections.capacity(), 1000);
+    }
+
+    // How often the connection slab grows while accepting `count` connections.
+    fn regrowths(settings: Settings, count: usize) -> usize {
+        let mut poll = Poll::new().unwrap();
+        let mut handler = Handler::new(|_| H, settings);
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut clients = Vec::new();
+        let mut capacity = handler.connections.capacity();
+        let mut grown = 0;
+        for _ in 0..count {
+            clients.push(net::TcpStream::connect(addr).unwrap());
+            let (sock, _) = listener.accept().unwrap();
+            handler.accept(&mut poll, TcpStream::from_stream(sock).unwrap()).unwrap();
+            if handler.connections.capacity() != capacity {
+                capacity = handler.connections.capacity();
+                grown += 1;
+            }
+        }
+        assert_eq!(handler.connections.len(), count);
+        grown
+    }
+
+    #[test]
+    fn presized_connections_never_regrow() {
+        // more connections than the default initial capacity of 100
+        let settings = Settings {
+            max_connections: 1000,
+            ..Settings::default()
+        };
+        assert!(regrowths(settings, 150) > 0);
+        let settings = Settings {
+            max_connections: 1000,
+            initial_connection_capacity: 150,
+            ..Settings::default()
+        };
+        assert_eq!(regrowths(settings, 150), 0);
+    }
+
+    #[test]
+    fn initial_capacity_capped_at_max() {
+        let settings = Settings {
+            max_connections: 10,
+            initial_connection_capacity: 1000,
+            ..Settings::default()
+        };
+        let handler = Handler::new(|_| H, settings);
+        assert_eq!(handler.connections.capacity(), 10);
+    }
+
+    #[test]
+    fn transient_accept_errors() {
+        for errno in RESOURCES_EXHAUSTED.iter() {
+            assert!(is_transient_accept_error(&IoError::from_raw_os_error(*errno)));
+        }
+        assert!(!is_transient_accept_error(&IoError::from_raw_os_error(CONNECTION_REFUSED)));
+        assert!(!is_transient_accept_error(&IoError::new(ErrorKind::WouldBlock, "would block")));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fastopen_listener() {
+        use std::os::unix::io::AsRawFd;
+
+        let settings = Settings {
+            tcp_fastopen: true,
+            tcp_fastopen_backlog: 16,
+            ..Settings::default()
+        };
+        let listener = build_listener(&"127.0.0.1:0".parse().unwrap(), &settings).unwrap();
+
+        let mut qlen: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let value: *mut libc::c_int = &mut qlen;
+        let ret = unsafe {
+            libc::getsockopt(listener.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_FASTOPEN,
+                             value as *mut libc::c_void, &mut len)
+        };
+        assert_eq!(0, ret);
+        assert_eq!(16, qlen);
+    }
+}
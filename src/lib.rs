@@ -13,6 +13,9 @@ extern crate url;
 extern crate slab;
 extern crate bytes;
 extern crate byteorder;
+#[cfg(unix)]
+extern crate libc;
+extern crate net2;
 #[macro_use]
 extern crate log;
 
@@ -34,17 +37,21 @@ pub mod util;
 pub use factory::Factory;
 pub use handler::Handler;
 
-pub use result::{Result, Error};
+pub use result::{Result, Error, Severity, close_code_for};
 pub use result::Kind as ErrorKind;
-pub use message::Message;
-pub use communication::Sender;
+pub use message::{Message, PreparedMessage};
+pub use communication::{Sender, Diagnostics};
 pub use protocol::{CloseCode, OpCode};
 
 
 use std::fmt;
 use std::default::Default;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{self, SocketAddr, ToSocketAddrs};
 use std::borrow::Borrow;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
 
 use mio::Poll;
 
@@ -125,6 +132,24 @@ pub struct Settings {
     /// this limit can be made until an old connection is dropped.
     /// Default: 100
     pub max_connections: usize,
+    /// What to do with new connections once `max_connections` is reached.
+    /// Default: AcceptPolicy::Refuse
+    pub accept_policy: AcceptPolicy,
+    /// The maximum number of outgoing connections that may be queued with `connect` or
+    /// established at once. Once it is reached `connect` and `connect_addr` return a Capacity
+    /// error until one of them is dropped. Outgoing connections also count towards
+    /// `max_connections`.
+    /// Default: usize::MAX (only limited by `max_connections`)
+    pub max_outgoing_connections: usize,
+    /// What `Sender::send_to_label` does when several connections share the label it is given.
+    /// Default: DuplicateLabels::DeliverToAll
+    pub duplicate_labels: DuplicateLabels,
+    /// The number of connections to allocate room for up front. Servers expecting a large number
+    /// of connections can set this close to `max_connections` in order to avoid reallocating
+    /// during a connection storm. Room for further connections is allocated on demand up to
+    /// `max_connections`, and values above `max_connections` are capped at it.
+    /// Default: 100
+    pub initial_connection_capacity: usize,
     /// The number of events anticipated per connection. The event loop queue size will
     /// be `queue_size` * `max_connections`. In order to avoid an overflow error,
     /// `queue_size` * `max_connections` must be less than or equal to `usize::max_value()`.
@@ -133,6 +158,13 @@ pub struct Settings {
     /// `queue_size`. However, if the queue is maxed out a Queue error will occur.
     /// Default: 5
     pub queue_size: usize,
+    /// The largest number of queued commands, such as messages sent through a `Sender`, that
+    /// the event loop carries out before it handles socket events again. Commands are always
+    /// handled ahead of the socket events of the same iteration, and the rest of a large burst
+    /// is picked up in the following iterations, so that socket io isn't starved. Must be at
+    /// least 1, `Builder::build` returns a Capacity error otherwise.
+    /// Default: 256
+    pub max_commands_per_poll: usize,
     /// Whether to panic when unable to establish a new TCP connection.
     /// Default: false
     pub panic_on_new_connection: bool,
@@ -150,7 +182,7 @@ pub struct Settings {
     /// Default: 65,535
     pub fragment_size: usize,
     /// The size of the incoming buffer. A larger buffer uses more memory but will allow for fewer
-    /// reallocations.
+    /// reallocations. Must be at least 1, `Builder::build` returns a Capacity error otherwise.
     /// Default: 2048
     pub in_buffer_capacity: usize,
     /// Whether to reallocate the incoming buffer when `in_buffer_capacity` is reached. If this is
@@ -158,18 +190,43 @@ pub struct Settings {
     /// Default: true
     pub in_buffer_grow: bool,
     /// The size of the outgoing buffer. A larger buffer uses more memory but will allow for fewer
-    /// reallocations.
+    /// reallocations. Must be at least 1, `Builder::build` returns a Capacity error otherwise.
     /// Default: 2048
     pub out_buffer_capacity: usize,
     /// Whether to reallocate the incoming buffer when `out_buffer_capacity` is reached. If this is
     /// false, a Capacity error will be triggered instead.
     /// Default: true
     pub out_buffer_grow: bool,
+    /// An absolute limit on the number of unsent bytes buffered for a connection, independent of
+    /// `out_buffer_capacity` and `out_buffer_grow`. A peer that stops reading will cause the
+    /// outgoing buffer to fill up, and once this limit would be exceeded the connection is closed
+    /// with a Policy (1008) close code and dropped, and the handler receives a Capacity error.
+    /// Default: None
+    pub max_out_buffer_bytes: Option<usize>,
+    /// The number of bytes after which a connection stops reading for the current event loop
+    /// iteration, even if more data is available. The rest is read in a later iteration, after
+    /// the other connections had their turn, so a peer that sends continuously can't starve
+    /// them. The limit is checked after each read, which reads up to the free space of the
+    /// incoming buffer, so a little more than this may be read.
+    /// Default: None
+    pub max_read_bytes_per_poll: Option<usize>,
+    /// A limit on the memory used for the incoming and outgoing buffers of all connections
+    /// together. Every connection still starts out with `in_buffer_capacity` and
+    /// `out_buffer_capacity` bytes, but a buffer that would grow beyond this budget triggers a
+    /// Capacity error instead, even if `in_buffer_grow` or `out_buffer_grow` is set. Shared
+    /// broadcast data is not counted.
+    /// Default: None
+    pub global_buffer_budget: Option<usize>,
     /// Whether to panic when an Internal error is encountered. Internal errors should generally
     /// not occur, so this setting defaults to true as a debug measure, whereas production
     /// applications should consider setting it to false.
     /// Default: true
     pub panic_on_internal: bool,
+    /// Whether the default `Handler::on_error` prints unexpected errors to stdout when no logger
+    /// is enabled. Applications that capture stdout or must not write to it, such as daemons,
+    /// should turn this off and rely on logging or their own `on_error` instead.
+    /// Default: true
+    pub error_stdout_fallback: bool,
     /// Whether to panic when a Capacity error is encountered.
     /// Default: false
     pub panic_on_capacity: bool,
@@ -188,22 +245,86 @@ pub struct Settings {
     /// Whether to panic when a Timer error is encountered.
     /// Default: false
     pub panic_on_timeout: bool,
+    /// The largest number of connections that a broadcast is buffered on at once. Broadcasting
+    /// to more connections than this is spread over several iterations of the event loop, so
+    /// that reads, timeouts and other events are handled in between. Each connection still
+    /// receives broadcasts and direct messages in the order they were sent. Must be at least 1,
+    /// `Builder::build` returns a Capacity error otherwise.
+    /// Default: usize::MAX (buffer every broadcast on all connections at once)
+    pub broadcast_batch_size: usize,
+    /// Whether to catch panics in the factory while it creates the handler for a new connection.
+    /// When a panic is caught only that connection is rejected: its socket is closed and the
+    /// error is logged, while the event loop and all other connections carry on.
+    /// Default: false
+    pub catch_handler_panics: bool,
+    /// The longest time in milliseconds that the event loop will block waiting for events. Timers
+    /// scheduled with `Sender::timeout` wake the event loop on their own, so this is only needed
+    /// as a safety net for environments where that wakeup could be missed. Due timeouts are
+    /// always serviced on each wakeup, even if no other events arrived.
+    /// Default: None (block until the next event)
+    pub max_poll_timeout_ms: Option<u64>,
+    /// The resolution of timeouts in milliseconds. Timeouts fire on the first tick after they
+    /// are due, so a shorter tick makes them more punctual at the cost of waking the event loop
+    /// more often. Must be at least 1.
+    /// Default: 100
+    pub timer_tick_ms: u64,
+    /// The maximum number of timeouts that may be pending at once, over all connections and
+    /// including those set by `Sender::set_read_timeout` and `Sender::set_write_timeout`. Once
+    /// it is reached, scheduling another timeout fails with a Timer error until one fires or is
+    /// cancelled. Applications that use many timeouts per connection should raise it.
+    /// Default: 65,536
+    pub timer_capacity: usize,
     /// Whether to shutdown the eventloop when an interrupt is received.
     /// Default: true
     pub shutdown_on_interrupt: bool,
+    /// Whether to set `SO_REUSEPORT` on the listening socket before binding it. This lets several
+    /// WebSockets, typically one per thread, bind the same address, with the kernel spreading
+    /// new connections between them. Each WebSocket then only sees its own connections, so a
+    /// broadcast only reaches the connections of the WebSocket it was sent on.
+    /// Only supported on Unix and only takes effect on platforms that implement the option, such
+    /// as Linux 3.9 and later and the BSDs; elsewhere `bind` returns an error.
+    /// Default: false
+    pub tcp_reuse_port: bool,
+    /// Whether to enable TCP Fast Open on the listening socket, which lets clients that support
+    /// it send data along with their SYN and saves a round trip on repeat connections.
+    /// Only supported on Linux, where the kernel also needs to allow it for servers through the
+    /// `net.ipv4.tcp_fastopen` sysctl. Elsewhere, or if the socket refuses the option, a warning
+    /// is logged and the socket listens without it. Has no effect on sockets given to
+    /// `bind_fd` or `bind_tcp`.
+    /// Default: false
+    pub tcp_fastopen: bool,
+    /// The length of the queue of Fast Open connections that have not completed the TCP
+    /// handshake yet. Only used when `tcp_fastopen` is set.
+    /// Default: 256
+    pub tcp_fastopen_backlog: usize,
     /// Disables Nagle's algorithm.
     /// Usually tcp socket tries to accumulate packets to send them all together (every 200ms).
     /// When enabled socket will try to send packet as fast as possible.
     ///
     /// Default: false
-    pub tcp_nodelay: bool
+    pub tcp_nodelay: bool,
+    /// The size in bytes to request for the send buffer (`SO_SNDBUF`) of each connection's
+    /// socket. The OS may clamp the size or refuse it, in which case a warning is logged and the
+    /// connection goes ahead with whatever size the socket has.
+    /// Default: None (use the OS default)
+    pub tcp_send_buffer_size: Option<usize>,
+    /// The size in bytes to request for the receive buffer (`SO_RCVBUF`) of each connection's
+    /// socket. The OS may clamp the size or refuse it, in which case a warning is logged and the
+    /// connection goes ahead with whatever size the socket has.
+    /// Default: None (use the OS default)
+    pub tcp_recv_buffer_size: Option<usize>,
 }
 
 impl Default for Settings {
     fn default() -> Settings {
         Settings {
             max_connections: 100,
+            accept_policy: AcceptPolicy::Refuse,
+            max_outgoing_connections: usize::MAX,
+            duplicate_labels: DuplicateLabels::DeliverToAll,
+            initial_connection_capacity: 100,
             queue_size: 5,
+            max_commands_per_poll: 256,
             panic_on_new_connection: false,
             panic_on_shutdown: false,
             fragments_capacity: 10,
@@ -213,19 +334,53 @@ impl Default for Settings {
             in_buffer_grow: true,
             out_buffer_capacity: 2048,
             out_buffer_grow: true,
+            max_out_buffer_bytes: None,
+            max_read_bytes_per_poll: None,
+            global_buffer_budget: None,
             panic_on_internal: true,
+            error_stdout_fallback: true,
             panic_on_capacity: false,
             panic_on_protocol: false,
             panic_on_encoding: false,
             panic_on_queue: false,
             panic_on_io: false,
             panic_on_timeout: false,
+            broadcast_batch_size: usize::MAX,
+            catch_handler_panics: false,
+            max_poll_timeout_ms: None,
+            timer_tick_ms: 100,
+            timer_capacity: 65_536,
             shutdown_on_interrupt: true,
-            tcp_nodelay: false
+            tcp_reuse_port: false,
+            tcp_fastopen: false,
+            tcp_fastopen_backlog: 256,
+            tcp_nodelay: false,
+            tcp_send_buffer_size: None,
+            tcp_recv_buffer_size: None,
         }
     }
 }
 
+/// How a WebSocket treats new connections while it is at `max_connections`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptPolicy {
+    /// Accept new connections and close them right away.
+    Refuse,
+    /// Stop accepting until a connection is dropped. New connections wait in the listen backlog
+    /// of the kernel, so clients see a slow connect rather than a closed connection. Once the
+    /// backlog is full the OS may still refuse them.
+    Backpressure,
+}
+
+/// How `Sender::send_to_label` treats a label that is set on more than one connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateLabels {
+    /// Send the message to every connection with the label.
+    DeliverToAll,
+    /// Send the message to none of them and report an Internal error instead.
+    Reject,
+}
+
 
 /// The WebSocket struct. A WebSocket can support multiple incoming and outgoing connections.
 pub struct WebSocket<F>
@@ -267,6 +422,55 @@ impl<F> WebSocket<F>
         Err(last_error)
     }
     
+    /// Consume the WebSocket and accept connections on an already listening socket instead of
+    /// binding a new one. This supports systemd socket activation and sockets handed over by a
+    /// previous process during a restart.
+    /// The WebSocket takes ownership of `fd` and closes it when dropped. An error is returned,
+    /// leaving `fd` open and owned by the caller, if it isn't a socket that is listening for
+    /// connections or if it can't be registered with the event loop.
+    /// After the socket is adopted you should start the server using `run`.
+    #[cfg(unix)]
+    pub fn bind_fd(mut self, fd: RawFd) -> Result<WebSocket<F>> {
+        self.handler.listen_fd(&mut self.poll, fd)?;
+        if let Ok(addr) = self.handler.local_addr() {
+            info!("Listening for new connections on {}.", addr);
+        }
+        Ok(self)
+    }
+
+    /// Consume the WebSocket and listen for new connections on an already listening socket.
+    /// See `bind_fd` for the requirements on `fd`.
+    ///
+    /// # Safety
+    ///
+    /// This method will block until the event loop finishes running.
+    #[cfg(unix)]
+    pub fn listen_fd(self, fd: RawFd) -> Result<WebSocket<F>> {
+        self.bind_fd(fd).and_then(|server| server.run())
+    }
+
+    /// Consume the WebSocket and accept connections on a listener that was set up elsewhere,
+    /// for instance with socket options that `bind` doesn't provide. The listener is switched to
+    /// non-blocking mode.
+    /// After the listener is adopted you should start the server using `run`.
+    pub fn bind_tcp(mut self, listener: net::TcpListener) -> Result<WebSocket<F>> {
+        self.handler.listen_tcp(&mut self.poll, listener)?;
+        if let Ok(addr) = self.handler.local_addr() {
+            info!("Listening for new connections on {}.", addr);
+        }
+        Ok(self)
+    }
+
+    /// Consume the WebSocket and listen for new connections on a listener that was set up
+    /// elsewhere. See `bind_tcp`.
+    ///
+    /// # Safety
+    ///
+    /// This method will block until the event loop finishes running.
+    pub fn listen_tcp(self, listener: net::TcpListener) -> Result<WebSocket<F>> {
+        self.bind_tcp(listener).and_then(|server| server.run())
+    }
+
     /// Consume the WebSocket and listen for new connections on the specified address.
     ///
     /// # Safety
@@ -303,7 +507,9 @@ impl<F> WebSocket<F>
     }
     
     /// Get a Sender that can be used to send messages on all connections.
-    /// Calling `send` on this Sender is equivalent to calling `broadcast`.
+    /// Calling `send` on this Sender is equivalent to calling `broadcast`, unlike on the Sender
+    /// that a handler gets for its own connection. Use `Sender::is_broadcaster` to tell the two
+    /// apart.
     /// Calling `shutdown` on this Sender will shutdown the WebSocket even if no connections have
     /// been established.
     #[inline]
@@ -339,12 +545,56 @@ impl Builder {
     pub fn build<F>(&self, factory: F) -> Result<WebSocket<F>>
                     where F: Factory
     {
+        if self.settings.in_buffer_capacity == 0 || self.settings.out_buffer_capacity == 0 {
+            return Err(Error::new(ErrorKind::Capacity, "Buffer capacities must be at least one byte."))
+        }
+        if self.settings.broadcast_batch_size == 0 {
+            return Err(Error::new(ErrorKind::Capacity, "The broadcast batch size must be at least one connection."))
+        }
+        if self.settings.max_commands_per_poll == 0 {
+            return Err(Error::new(ErrorKind::Capacity, "At least one command must be handled per poll."))
+        }
+        if self.settings.timer_tick_ms == 0 {
+            return Err(Error::new(ErrorKind::Timer(mio::timer::TimerError), "The timer tick must be at least one millisecond."))
+        }
         Ok(WebSocket {
             poll: Poll::new()?,
             handler: io::Handler::new(factory, self.settings),
         })
     }
     
+    /// Build a WebSocket using this builder and a factory, and run its event loop on a new
+    /// thread named `ws-event-loop`. Returns a broadcaster for the WebSocket, which can be used to
+    /// open connections with `connect` and to stop the event loop with `shutdown`, and a handle
+    /// for joining the thread once the event loop finishes.
+    ///
+    /// The WebSocket is built on the new thread, so only the factory needs to be `Send`.
+    pub fn build_threaded<F>(&self, factory: F) -> Result<(Sender, JoinHandle<Result<()>>)>
+                             where F: Factory + Send + 'static
+    {
+        let builder = *self;
+        let (tx, rx) = mpsc::channel();
+
+        let handle = thread::Builder::new().name("ws-event-loop".into()).spawn(move || {
+            let ws = match builder.build(factory) {
+                Ok(ws) => ws,
+                Err(err) => {
+                    let reason = format!("Unable to build WebSocket: {}", err);
+                    let _ = tx.send(Err(err));
+                    return Err(Error::new(ErrorKind::Internal, reason))
+                }
+            };
+            let _ = tx.send(Ok(ws.broadcaster()));
+            ws.run().map(|_| ())
+        })?;
+
+        match rx.recv() {
+            Ok(Ok(sender)) => Ok((sender, handle)),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(Error::new(ErrorKind::Internal, "Event loop thread exited before starting")),
+        }
+    }
+
     /// Set the WebSocket settings to use.
     pub fn with_settings(&mut self, settings: Settings) -> &mut Builder {
         self.settings = settings;
@@ -2,6 +2,7 @@ use std::fmt;
 use std::convert::{From, Into};
 use std::str::from_utf8;
 use std::result::Result as StdResult;
+use std::sync::Arc;
 
 use protocol::OpCode;
 use result::Result;
@@ -9,7 +10,10 @@ use result::Result;
 use self::Message::*;
 
 /// An enum representing the various forms of a WebSocket message.
-#[derive(Debug, Eq, PartialEq, Clone)]
+///
+/// Messages can be compared and hashed, so they can be deduplicated or used as map keys. A text
+/// message is never equal to a binary message, even when both contain the same bytes.
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
 pub enum Message {
     /// A text WebSocket message
     Text(String),
@@ -81,6 +85,14 @@ impl Message {
         }
     }
 
+    /// Consume the WebSocket message and prepare it for being sent many times, see
+    /// `Sender::send_prepared` and `Sender::broadcast_prepared`.
+    pub fn prepare(self) -> PreparedMessage {
+        PreparedMessage {
+            data: Arc::new(self.into_data()),
+        }
+    }
+
     /// Attempt to consume the WebSocket message and convert it to a String.
     pub fn into_text(self) -> Result<String> {
         match self {
@@ -99,6 +111,31 @@ impl Message {
     }
 }
 
+/// The bytes of a message, ready to be sent. Cloning a prepared message or sending it doesn't
+/// copy the bytes, which makes it cheap to send the same message repeatedly, such as a periodic
+/// snapshot that is broadcast to every connection.
+#[derive(Debug, Clone)]
+pub struct PreparedMessage {
+    data: Arc<Vec<u8>>,
+}
+
+impl PreparedMessage {
+    /// Get the length of the prepared message.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns true if the prepared message has no content.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    #[doc(hidden)]
+    pub fn shared(&self) -> Arc<Vec<u8>> {
+        self.data.clone()
+    }
+}
+
 impl From<String> for Message {
     fn from(string: String) -> Message {
         Message::text(string)
@@ -148,6 +185,14 @@ mod test {
         assert_eq!(bin.to_string(), "Binary Data<length=5>".to_owned());
     }
 
+    #[test]
+    fn prepared_shares_data() {
+        let prepared = Message::text("snapshot").prepare();
+        let copy = prepared.clone();
+        assert_eq!(copy.len(), 8);
+        assert!(Arc::ptr_eq(&prepared.shared(), &copy.shared()));
+    }
+
     #[test]
     fn binary_convert() {
         let bin = [6u8, 7, 8, 9, 10, 241];
@@ -165,6 +210,18 @@ mod test {
         assert!(msg.into_text().is_err());
     }
 
+    #[test]
+    fn hash_distinguishes_variants() {
+        use std::collections::HashSet;
+
+        let mut set = HashSet::new();
+        assert!(set.insert(Message::text("kiwotsukete")));
+        assert!(set.insert(Message::binary("kiwotsukete")));
+        assert!(!set.insert(Message::text("kiwotsukete")));
+        assert_eq!(set.len(), 2);
+        assert!(Message::text("kiwotsukete") != Message::binary("kiwotsukete"));
+    }
+
     #[test]
     fn text_convert() {
         let s = "kiwotsukete";
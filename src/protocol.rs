@@ -1,6 +1,8 @@
 use std::fmt;
 use std::convert::{Into, From};
 
+use result::{Result, Error, Kind};
+
 use self::OpCode::*;
 /// Operation codes as part of rfc6455.
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -23,6 +25,16 @@ pub enum OpCode {
 
 impl OpCode {
 
+    /// Parse an opcode from the low four bits of a frame header. Unlike the `From<u8>`
+    /// conversion, which maps them to `Bad`, reserved opcodes are rejected with a Protocol error.
+    /// Every opcode returned by this method converts back into the same byte.
+    pub fn parse(byte: u8) -> Result<OpCode> {
+        match OpCode::from(byte) {
+            Bad => Err(Error::new(Kind::Protocol, format!("Encountered invalid opcode: {}", byte))),
+            code => Ok(code),
+        }
+    }
+
     /// Test whether the opcode indicates a control frame.
     pub fn is_control(&self) -> bool {
         match *self {
@@ -31,6 +43,14 @@ impl OpCode {
         }
     }
 
+    /// Test whether the opcode indicates a data frame, including continuation frames.
+    pub fn is_data(&self) -> bool {
+        match *self {
+            Text | Binary | Continue => true,
+            _ => false,
+        }
+    }
+
 }
 
 impl fmt::Display for OpCode {
@@ -142,6 +162,9 @@ pub enum CloseCode {
     /// to a different IP (when multiple targets exist), or reconnect to the same IP
     /// when a user has performed an action.
     Again,
+    /// Indicates that the server was acting as a gateway or proxy and received an
+    /// invalid response from the upstream server.
+    BadGateway,
     #[doc(hidden)]
     Tls,
     #[doc(hidden)]
@@ -167,6 +190,7 @@ impl Into<u16> for CloseCode {
            Error         =>   1011,
            Restart       =>   1012,
            Again         =>   1013,
+           BadGateway    =>   1014,
            Tls           =>   1015,
            Empty         =>   0,
            Other(code)   =>   code,
@@ -191,6 +215,7 @@ impl From<u16> for CloseCode {
             1011 => Error,
             1012 => Restart,
             1013 => Again,
+            1014 => BadGateway,
             1015 => Tls,
             0    => Empty,
             _ => Other(code),
@@ -216,12 +241,52 @@ mod test {
         assert_eq!(byte, 1u8);
     }
 
+    #[test]
+    fn opcode_round_trip() {
+        for &code in &[Continue, Text, Binary, Close, Ping, Pong] {
+            let byte: u8 = code.into();
+            assert_eq!(OpCode::parse(byte).unwrap(), code);
+            assert_eq!(OpCode::from(byte), code);
+        }
+    }
+
+    #[test]
+    fn opcode_reserved() {
+        for byte in (3u8..8).chain(11..16) {
+            let err = OpCode::parse(byte).unwrap_err();
+            match err.kind {
+                Kind::Protocol => (),
+                _ => panic!("Reserved opcode {} gave the wrong error: {}", byte, err),
+            }
+        }
+    }
+
+    #[test]
+    fn opcode_classes() {
+        for &code in &[Continue, Text, Binary] {
+            assert!(code.is_data() && !code.is_control());
+        }
+        for &code in &[Close, Ping, Pong] {
+            assert!(code.is_control() && !code.is_data());
+        }
+        assert!(!Bad.is_data());
+    }
+
     #[test]
     fn closecode_from_u16() {
         let byte = 1008u16;
         assert_eq!(CloseCode::from(byte), CloseCode::Policy);
     }
 
+    #[test]
+    fn closecode_round_trip() {
+        for &(code, byte) in &[(Restart, 1012u16), (Again, 1013), (BadGateway, 1014), (Tls, 1015)] {
+            assert_eq!(CloseCode::from(byte), code);
+            let into: u16 = code.into();
+            assert_eq!(into, byte);
+        }
+    }
+
     #[test]
     fn closecode_into_u16() {
         let text = CloseCode::Away;
@@ -8,9 +8,11 @@ use std::convert::{From, Into};
 
 use httparse;
 use mio;
+use url;
 
 
 use communication::Command;
+use protocol::CloseCode;
 
 pub type Result<T> = StdResult<T, Error>;
 
@@ -40,6 +42,11 @@ pub enum Kind {
     /// This kind of error should only occur during a WebSocket Handshake, and a HTTP 500 response
     /// will be generated.
     Http(httparse::Error),
+    /// Indicates a failure to parse the URL of a new connection.
+    Url(url::ParseError),
+    /// Indicates that no connection could be found for an address given by the application,
+    /// such as the label passed to `Sender::send_to_label`.
+    NotFound,
     /// Indicates a failure to send a signal on the internal EventLoop channel. This means that
     /// the WebSocket is overloaded. In order to avoid this error, it is important to set
     /// `Settings::max_connections` and `Settings:queue_size` high enough to handle the load.
@@ -54,6 +61,40 @@ pub enum Kind {
     Custom(Box<StdError + Send + Sync>),
 }
 
+/// The close code that corresponds to an error of the given kind.
+///
+/// When an Internal, Capacity, Protocol or Encoding error occurs on an open connection, the
+/// WebSocket closes the connection with this close code. The other kinds don't trigger a close
+/// by the WebSocket itself, but map to the code a handler would most reasonably use to close
+/// because of them: Io errors mean the connection was lost (Abnormal), Http errors mean the
+/// other endpoint misbehaved (Protocol), and the remaining kinds are local failures (Error).
+pub fn close_code_for(kind: &Kind) -> CloseCode {
+    match *kind {
+        Kind::Internal      => CloseCode::Error,
+        Kind::Capacity      => CloseCode::Size,
+        Kind::Protocol      => CloseCode::Protocol,
+        Kind::Encoding(_)   => CloseCode::Invalid,
+        Kind::Io(_)         => CloseCode::Abnormal,
+        Kind::Http(_)       => CloseCode::Protocol,
+        Kind::Url(_)        => CloseCode::Error,
+        Kind::NotFound      => CloseCode::Error,
+        Kind::Queue(_)      => CloseCode::Error,
+        Kind::Timer(_)      => CloseCode::Error,
+        Kind::Custom(_)     => CloseCode::Error,
+    }
+}
+
+/// How serious an error is. This decides how loudly the default `Handler::on_error` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// An error that comes with normal operation, such as a peer resetting its connection or
+    /// sending malformed data. The default `on_error` logs these at debug level.
+    Expected,
+    /// An error that points to a problem with the application or the WebSocket itself. The
+    /// default `on_error` logs these at error level.
+    Unexpected,
+}
+
 /// A struct indicating the kind of error that has occured and any precise details of that error.
 pub struct Error {
     pub kind: Kind,
@@ -71,6 +112,16 @@ impl Error {
         }
     }
 
+    /// Classify this error. Errors caused by the other endpoint, such as protocol, encoding and
+    /// HTTP errors or a connection reset, are expected, everything else is unexpected.
+    pub fn severity(&self) -> Severity {
+        match self.kind {
+            Kind::Protocol | Kind::Encoding(_) | Kind::Http(_) => Severity::Expected,
+            Kind::Io(ref err) if err.kind() == io::ErrorKind::ConnectionReset => Severity::Expected,
+            _ => Severity::Unexpected,
+        }
+    }
+
     pub fn into_box(self) -> Box<StdError> {
         match self.kind {
             Kind::Custom(err) => err,
@@ -110,6 +161,8 @@ impl StdError for Error {
             Kind::Encoding(ref err)     => err.description(),
             Kind::Io(ref err)           => err.description(),
             Kind::Http(_)               => "Unable to parse HTTP",
+            Kind::Url(ref err)          => err.description(),
+            Kind::NotFound              => "No such connection",
             Kind::Queue(_)              => "Unable to send signal on event loop",
             Kind::Timer(_)              => "Unable to schedule timeout on event loop",
             Kind::Custom(ref err)       => err.description(),
@@ -120,6 +173,7 @@ impl StdError for Error {
         match self.kind {
             Kind::Encoding(ref err) => Some(err),
             Kind::Io(ref err)       => Some(err),
+            Kind::Url(ref err)      => Some(err),
             Kind::Custom(ref err)   => Some(err.as_ref()),
             _ => None,
         }
@@ -150,6 +204,12 @@ impl From<httparse::Error> for Error {
 
 }
 
+impl From<url::ParseError> for Error {
+    fn from(err: url::ParseError) -> Error {
+        Error::new(Kind::Url(err), "")
+    }
+}
+
 impl From<mio::channel::SendError<Command>> for Error {
 
     fn from(err: mio::channel::SendError<Command>) -> Error {
@@ -185,3 +245,40 @@ impl<B> From<Box<B>> for Error
         Error::new(Kind::Custom(err), "")
     }
 }
+
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+
+    #[test]
+    fn close_codes() {
+        let utf8 = String::from_utf8(vec![0xC0]).unwrap_err().utf8_error();
+        let io = io::Error::new(io::ErrorKind::ConnectionReset, "io");
+
+        assert_eq!(close_code_for(&Kind::Internal), CloseCode::Error);
+        assert_eq!(close_code_for(&Kind::Capacity), CloseCode::Size);
+        assert_eq!(close_code_for(&Kind::Protocol), CloseCode::Protocol);
+        assert_eq!(close_code_for(&Kind::Encoding(utf8)), CloseCode::Invalid);
+        assert_eq!(close_code_for(&Kind::Io(io)), CloseCode::Abnormal);
+        assert_eq!(close_code_for(&Kind::Http(httparse::Error::Version)), CloseCode::Protocol);
+        assert_eq!(close_code_for(&Kind::Url(url::ParseError::EmptyHost)), CloseCode::Error);
+        assert_eq!(close_code_for(&Kind::NotFound), CloseCode::Error);
+        assert_eq!(close_code_for(&Kind::Timer(mio::timer::TimerError)), CloseCode::Error);
+        assert_eq!(close_code_for(&Kind::Custom(From::from("custom"))), CloseCode::Error);
+    }
+
+    #[test]
+    fn severities() {
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+
+        assert_eq!(Error::new(Kind::Protocol, "").severity(), Severity::Expected);
+        assert_eq!(Error::from(httparse::Error::Version).severity(), Severity::Expected);
+        assert_eq!(Error::from(reset).severity(), Severity::Expected);
+        assert_eq!(Error::from(refused).severity(), Severity::Unexpected);
+        assert_eq!(Error::new(Kind::Internal, "").severity(), Severity::Unexpected);
+        assert_eq!(Error::new(Kind::Capacity, "").severity(), Severity::Unexpected);
+    }
+}
@@ -66,7 +66,7 @@ impl Stream {
         Tcp(stream)
     }
     
-    
+    /// The raw socket, which is what gets registered with mio.
     pub fn evented(&self) -> &TcpStream {
         match *self {
             Tcp(ref sock) => sock,
@@ -86,15 +86,11 @@ impl Stream {
     }
     
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-        match *self {
-            Tcp(ref sock) => sock.peer_addr(),
-        }
+        self.evented().peer_addr()
     }
     
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        match *self {
-            Tcp(ref sock) => sock.local_addr(),
-        }
+        self.evented().local_addr()
     }
 }
 
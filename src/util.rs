@@ -1,4 +1,8 @@
-//! The util module rexports some tools from mio in order to facilitate handling timeouts.
+//! The util module rexports some tools from mio in order to facilitate handling timeouts, along
+//! with a few helpers for applications.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, BigEndian};
 use slab;
 
 /// Used to identify some timed-out event.
@@ -10,3 +14,127 @@ pub use mio::timer::Timeout;
 pub type Slab<T> = slab::Slab<T, Token>;
 
 pub use mio::tcp::TcpStream;
+
+/// Normalize a request path for routers that want to match paths loosely. ASCII letters are
+/// lowercased and trailing slashes are removed, except from the root path. Percent-encoded
+/// letters, digits and `-._~` are decoded, and other percent-encodings get uppercase hex digits.
+/// So `/Chat/`, `/chat` and `/%63hat` all become `/chat`. An encoded slash stays encoded, because
+/// decoding it would split a segment in two: `/a%2fb` becomes `/a%2Fb`.
+///
+/// The path is expected without a query string.
+pub fn normalize_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                let byte = hi << 4 | lo;
+                if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                    out.push(byte.to_ascii_lowercase());
+                } else {
+                    out.push(b'%');
+                    out.push(bytes[i + 1].to_ascii_uppercase());
+                    out.push(bytes[i + 2].to_ascii_uppercase());
+                }
+                i += 3;
+                continue
+            }
+        }
+        out.push(bytes[i].to_ascii_lowercase());
+        i += 1;
+    }
+    while out.len() > 1 && out.last() == Some(&b'/') {
+        out.pop();
+    }
+    if out.is_empty() {
+        out.push(b'/');
+    }
+    // only ASCII was replaced, so the path is still valid UTF-8
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The current time as milliseconds since the UNIX epoch, encoded as 8 big-endian bytes. This
+/// can be embedded in a message that the peer echoes back, so that the round-trip time can be
+/// computed with `millis_from_bytes` when the echo arrives.
+pub fn now_millis_bytes() -> Vec<u8> {
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let millis = since.as_secs() * 1000 + u64::from(since.subsec_millis());
+    let mut bytes = vec![0; 8];
+    BigEndian::write_u64(&mut bytes, millis);
+    bytes
+}
+
+/// Read back a time encoded by `now_millis_bytes` from the start of `bytes`, or `None` if there
+/// are fewer than 8 bytes.
+pub fn millis_from_bytes(bytes: &[u8]) -> Option<u64> {
+    if bytes.len() < 8 {
+        return None
+    }
+    Some(BigEndian::read_u64(bytes))
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+
+    #[test]
+    fn normalize_trailing_slashes() {
+        assert_eq!(normalize_path("/chat/"), "/chat");
+        assert_eq!(normalize_path("/chat//"), "/chat");
+        assert_eq!(normalize_path("/chat/room/"), "/chat/room");
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("//"), "/");
+        assert_eq!(normalize_path(""), "/");
+    }
+
+    #[test]
+    fn normalize_case() {
+        assert_eq!(normalize_path("/Chat"), "/chat");
+        assert_eq!(normalize_path("/CHAT/Room"), "/chat/room");
+        // only ASCII is folded
+        assert_eq!(normalize_path("/Ünïcode"), "/Ünïcode");
+    }
+
+    #[test]
+    fn normalize_percent_encoding() {
+        assert_eq!(normalize_path("/%63hat"), "/chat");
+        assert_eq!(normalize_path("/%43HAT"), "/chat");
+        assert_eq!(normalize_path("/a%2Db%5f%7E"), "/a-b_~");
+        // reserved and non-ASCII characters stay encoded, with uppercase hex digits
+        assert_eq!(normalize_path("/a%2fb"), "/a%2Fb");
+        assert_eq!(normalize_path("/caf%c3%a9"), "/caf%C3%A9");
+        assert_eq!(normalize_path("/a%20b"), "/a%20b");
+        // an encoded slash at the end is part of the segment, not a trailing slash
+        assert_eq!(normalize_path("/chat%2F"), "/chat%2F");
+        // malformed encodings are left as they are
+        assert_eq!(normalize_path("/100%"), "/100%");
+        assert_eq!(normalize_path("/%zz"), "/%zz");
+    }
+
+    #[test]
+    fn millis_round_trip() {
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() * 1000;
+        let bytes = now_millis_bytes();
+        assert_eq!(bytes.len(), 8);
+        let millis = millis_from_bytes(&bytes).unwrap();
+        assert!(millis >= before);
+        assert!(millis < before + 60_000);
+
+        let mut payload = bytes.clone();
+        payload.extend_from_slice(b"nonce");
+        assert_eq!(millis_from_bytes(&payload), Some(millis));
+        assert_eq!(millis_from_bytes(&bytes[..7]), None);
+    }
+}
@@ -0,0 +1,171 @@
+extern crate ws;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Sender, Message, Result, CloseCode};
+use ws::util::Token;
+
+struct Abuser {
+    out: Sender,
+    closed: ChannelSender<(CloseCode, String)>,
+}
+
+impl ws::Handler for Abuser {
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.out.abort()
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.closed.send((code, reason.to_owned())).unwrap();
+    }
+}
+
+#[test]
+fn abort_resets_connection() {
+    let (tx, rx) = channel();
+
+    let ws = ws::WebSocket::new(move |out| {
+        Abuser { out: out, closed: tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"spam").unwrap();
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), (CloseCode::Abnormal, "aborted".into()));
+
+    // nothing was sent before the reset
+    let mut buf = [0u8; 16];
+    match client.read(&mut buf) {
+        Err(ref err) if err.kind() == ErrorKind::ConnectionReset => (),
+        other => panic!("Expected the connection to be reset, got {:?}", other),
+    }
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn broadcast_abort_resets_every_connection() {
+    let (tx, rx) = channel();
+
+    let ws = ws::WebSocket::new(move |out| {
+        Abuser { out: out, closed: tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let clients: Vec<_> = (0..2).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    // let the server accept both before aborting
+    thread::sleep(Duration::from_millis(100));
+    handle.abort().unwrap();
+
+    for _ in 0..2 {
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().1, "aborted");
+    }
+    for mut client in clients {
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0u8; 16];
+        assert!(client.read(&mut buf).is_err());
+    }
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+const HANDOFF: Token = Token(1);
+
+struct Handoff {
+    out: Sender,
+    addr: SocketAddr,
+    events: ChannelSender<&'static str>,
+    // whether this is the connection to abort, the others just report opening
+    first: bool,
+}
+
+impl ws::Handler for Handoff {
+    fn on_open(&mut self) -> Result<()> {
+        if self.first {
+            // a timeout runs the handoff after the socket was read to the end
+            self.out.timeout(50, HANDOFF)
+        } else {
+            self.events.send("open").unwrap();
+            Ok(())
+        }
+    }
+
+    fn on_timeout(&mut self, _: Token) -> Result<()> {
+        // the new connection takes the token of the aborted one
+        self.out.abort()?;
+        self.out.connect_addr(self.addr)?;
+        // the peer writes while we wait, so the old socket has an event in the same poll
+        self.events.send("handoff").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: CloseCode, reason: &str) {
+        if reason == "aborted" {
+            self.events.send("aborted").unwrap();
+        }
+    }
+}
+
+struct HandoffFactory {
+    addr: SocketAddr,
+    events: ChannelSender<&'static str>,
+    made: usize,
+}
+
+impl ws::Factory for HandoffFactory {
+    type Handler = Handoff;
+
+    fn connection_made(&mut self, out: Sender) -> Handoff {
+        self.made += 1;
+        Handoff { out: out, addr: self.addr, events: self.events.clone(), first: self.made == 1 }
+    }
+}
+
+#[test]
+fn abort_and_connect_in_one_batch() {
+    let (tx, rx) = channel();
+
+    // bind first to learn the address the handler connects to
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let ws = ws::WebSocket::new(HandoffFactory { addr: addr, events: tx, made: 0 }).unwrap();
+    let ws = ws.bind_tcp(listener).unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("handoff"));
+    client.write_all(b"stale").unwrap();
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("aborted"));
+    // both ends of the new connection open
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("open"));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("open"));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
@@ -0,0 +1,52 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use ws::{Sender, Message, Result};
+
+const BATCH: usize = 100;
+const NOISE: usize = 1000;
+
+struct Burst {
+    out: Sender,
+}
+
+impl ws::Handler for Burst {
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        let noise = self.out.clone();
+        thread::spawn(move || {
+            for _ in 0..NOISE {
+                noise.broadcast(".").unwrap();
+            }
+        });
+        self.out.send_all((0..BATCH).map(|i| format!("<{:03}>", i)).collect())
+    }
+}
+
+#[test]
+fn batch_arrives_in_order_and_contiguously() {
+    let ws = ws::WebSocket::new(|out| Burst { out: out }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"go").unwrap();
+
+    let expected: String = (0..BATCH).map(|i| format!("<{:03}>", i)).collect();
+    let mut received = vec![0u8; expected.len() + NOISE];
+    client.read_exact(&mut received).unwrap();
+    let received = String::from_utf8(received).unwrap();
+
+    assert!(received.contains(&expected));
+    assert_eq!(received.matches('.').count(), NOISE);
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
@@ -0,0 +1,51 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Sender, Message, Result};
+
+struct Echo {
+    out: Sender,
+    received: ChannelSender<Message>,
+}
+
+impl ws::Handler for Echo {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.received.send(msg.clone()).unwrap();
+        self.out.send(msg)
+    }
+}
+
+#[test]
+fn invalid_utf8_is_delivered_as_binary() {
+    let (tx, rx) = channel();
+    let ws = ws::WebSocket::new(move |out| Echo { out: out, received: tx.clone() }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+
+    client.write_all(b"text").unwrap();
+    assert_eq!(Message::text("text"), rx.recv_timeout(Duration::from_secs(2)).unwrap());
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).unwrap();
+
+    let data = vec![0xff, 0x00, 0xc3, 0x28, 0xfe];
+    client.write_all(&data).unwrap();
+    assert_eq!(Message::binary(data.clone()), rx.recv_timeout(Duration::from_secs(2)).unwrap());
+    let mut buf = vec![0u8; data.len()];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(data, buf);
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
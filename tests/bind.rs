@@ -29,3 +29,160 @@ fn bind_try_multiple_addrs() {
     let local_addr = ws.local_addr().unwrap();
     assert_eq!(valid_addr, local_addr);
 }
+
+#[cfg(unix)]
+#[test]
+fn bind_adopted_fd() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::IntoRawFd;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let ws = ws::WebSocket::new(|out: ws::Sender| {
+        move |msg| out.send(msg)
+    }).unwrap();
+    let ws = ws.bind_fd(listener.into_raw_fd()).unwrap();
+    assert_eq!(addr, ws.local_addr().unwrap());
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"adopted").unwrap();
+    let mut buf = [0u8; 7];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"adopted");
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn bind_fd_rejects_non_listener() {
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let ws = ws::WebSocket::new(|_sender| Handler).unwrap();
+    assert!(ws.bind_fd(socket.as_raw_fd()).is_err());
+    // the socket is left open
+    assert!(socket.local_addr().is_ok());
+}
+
+#[test]
+fn bind_adopted_listener() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // std listeners start out blocking, which would stall the event loop if it were kept
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let ws = ws::WebSocket::new(|out: ws::Sender| {
+        move |msg| out.send(msg)
+    }).unwrap();
+    let ws = ws.bind_tcp(listener).unwrap();
+    assert_eq!(addr, ws.local_addr().unwrap());
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut first = TcpStream::connect(addr).unwrap();
+    let mut second = TcpStream::connect(addr).unwrap();
+    for client in [&mut first, &mut second].iter_mut() {
+        client.write_all(b"adopted").unwrap();
+        let mut buf = [0u8; 7];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"adopted");
+    }
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn bind_reuse_port() {
+    use std::collections::HashSet;
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::thread;
+
+    let settings = ws::Settings { tcp_reuse_port: true, ..ws::Settings::default() };
+    let tagged = |tag: &'static str| {
+        ws::Builder::new().with_settings(settings).build(move |out: ws::Sender| {
+            out.send(tag).unwrap();
+            Handler
+        }).unwrap()
+    };
+
+    let a = tagged("a").bind("127.0.0.1:0").unwrap();
+    let addr = a.local_addr().unwrap();
+    let b = tagged("b").bind(addr).unwrap();
+    assert_eq!(addr, b.local_addr().unwrap());
+
+    let handles = vec![a.broadcaster(), b.broadcaster()];
+    let threads = vec![
+        thread::spawn(move || a.run().unwrap()),
+        thread::spawn(move || b.run().unwrap()),
+    ];
+
+    // the kernel spreads connections by hashing their addresses, so both are hit eventually
+    let mut seen = HashSet::new();
+    for _ in 0..64 {
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut buf = [0u8; 1];
+        client.read_exact(&mut buf).unwrap();
+        seen.insert(buf[0]);
+        if seen.len() == 2 {
+            break
+        }
+    }
+    assert_eq!(2, seen.len());
+
+    for handle in handles {
+        handle.shutdown().unwrap();
+    }
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+// Fast Open is only a hint, so normal connections work whether or not the platform supports it
+#[test]
+fn bind_fastopen() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    let settings = ws::Settings { tcp_fastopen: true, ..ws::Settings::default() };
+    let ws = ws::Builder::new().with_settings(settings).build(|out: ws::Sender| {
+        move |msg| out.send(msg)
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"ping").unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
@@ -0,0 +1,60 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Sender, Message, Result};
+
+struct Reply {
+    out: Sender,
+}
+
+impl ws::Handler for Reply {
+    fn on_open(&mut self) -> Result<()> {
+        assert!(!self.out.is_broadcaster());
+        self.out.send("hi")
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.out.send("re")
+    }
+}
+
+fn read(client: &mut TcpStream, len: usize) -> String {
+    let mut buf = vec![0u8; len];
+    client.read_exact(&mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+#[test]
+fn broadcaster_sends_to_all() {
+    let ws = ws::WebSocket::new(|out| Reply { out: out }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+    assert!(handle.is_broadcaster());
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut first = TcpStream::connect(addr).unwrap();
+    assert_eq!(read(&mut first, 2), "hi");
+    let mut second = TcpStream::connect(addr).unwrap();
+    assert_eq!(read(&mut second, 2), "hi");
+
+    // a connection's own Sender only reaches that connection
+    first.write_all(b"go").unwrap();
+    assert_eq!(read(&mut first, 2), "re");
+
+    // while the broadcaster reaches every connection
+    handle.send("all").unwrap();
+    assert_eq!(read(&mut first, 3), "all");
+    second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(read(&mut second, 3), "all");
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
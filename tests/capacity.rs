@@ -0,0 +1,206 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use ws::{AcceptPolicy, Builder, Settings};
+
+#[test]
+fn connections_grow_up_to_max() {
+    let ws = Builder::new().with_settings(Settings {
+        max_connections: 3,
+        initial_connection_capacity: 1,
+        ..Settings::default()
+    }).build(|out: ws::Sender| {
+        move |msg| out.send(msg)
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut clients = Vec::new();
+    for _ in 0..3 {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        clients.push(client);
+    }
+
+    // there is no room for a fourth connection
+    let mut client = TcpStream::connect(addr).unwrap();
+    let mut buf = Vec::new();
+    assert_eq!(client.read_to_end(&mut buf).unwrap_or(0), 0);
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+#[test]
+fn backpressure_at_max() {
+    let ws = Builder::new().with_settings(Settings {
+        max_connections: 2,
+        accept_policy: AcceptPolicy::Backpressure,
+        ..Settings::default()
+    }).build(|out: ws::Sender| {
+        move |msg| out.send(msg)
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut clients = Vec::new();
+    for _ in 0..2 {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        clients.push(client);
+    }
+
+    // the third connection waits in the backlog instead of being closed
+    let mut waiting = TcpStream::connect(addr).unwrap();
+    waiting.write_all(b"ping").unwrap();
+    waiting.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let mut buf = [0u8; 4];
+    let err = waiting.read(&mut buf).unwrap_err();
+    assert!(err.kind() == ::std::io::ErrorKind::WouldBlock || err.kind() == ::std::io::ErrorKind::TimedOut);
+
+    // and is accepted once a slot frees up
+    drop(clients.remove(0));
+    waiting.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    waiting.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+#[test]
+fn socket_buffer_sizes() {
+    let ws = Builder::new().with_settings(Settings {
+        tcp_send_buffer_size: Some(64 << 10),
+        // far beyond what the OS allows, so it gets clamped
+        tcp_recv_buffer_size: Some(1 << 30),
+        ..Settings::default()
+    }).build(|out: ws::Sender| {
+        move |msg| out.send(msg)
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"ping").unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn global_buffer_budget() {
+    let ws = Builder::new().with_settings(Settings {
+        in_buffer_capacity: 1024,
+        out_buffer_capacity: 1024,
+        // the initial buffers of three connections, with room for one of them to grow a little
+        global_buffer_budget: Some(3 * 2048 + 8192),
+        ..Settings::default()
+    }).build(|out: ws::Sender| {
+        move |msg: ws::Message| {
+            let len = msg.as_text()?.parse().unwrap();
+            out.send(vec![7u8; len])
+        }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut clients: Vec<_> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+    clients[0].write_all(b"4000").unwrap();
+    let mut buf = vec![0u8; 4000];
+    clients[0].read_exact(&mut buf).unwrap();
+
+    // growing another buffer this much would exceed the budget, so the connection is closed
+    clients[1].write_all(b"65536").unwrap();
+    let mut buf = Vec::new();
+    assert_eq!(clients[1].read_to_end(&mut buf).unwrap_or(0), 0);
+
+    // the other connections are unaffected
+    clients[2].write_all(b"100").unwrap();
+    let mut buf = vec![0u8; 100];
+    clients[2].read_exact(&mut buf).unwrap();
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn zero_buffer_capacity_rejected() {
+    for &(in_capacity, out_capacity) in &[(0, 2048), (2048, 0)] {
+        let res = Builder::new().with_settings(Settings {
+            in_buffer_capacity: in_capacity,
+            out_buffer_capacity: out_capacity,
+            ..Settings::default()
+        }).build(|out: ws::Sender| {
+            move |msg| out.send(msg)
+        });
+        match res {
+            Err(ws::Error { kind: ws::ErrorKind::Capacity, .. }) => (),
+            Err(err) => panic!("Expected a Capacity error, got {:?}", err),
+            Ok(_) => panic!("Built a WebSocket with a zero capacity buffer"),
+        }
+    }
+}
+
+#[test]
+fn one_byte_buffers() {
+    let ws = Builder::new().with_settings(Settings {
+        in_buffer_capacity: 1,
+        in_buffer_grow: false,
+        out_buffer_capacity: 1,
+        ..Settings::default()
+    }).build(|out: ws::Sender| {
+        move |msg| out.send(msg)
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    // the message is read a byte at a time, and each byte is echoed on its own
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"ping").unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
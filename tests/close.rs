@@ -0,0 +1,127 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Receiver, Sender as ChannelSender};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Sender, Message, Result, CloseCode};
+
+struct Leaving {
+    out: Sender,
+    closed: ChannelSender<(CloseCode, String)>,
+}
+
+impl ws::Handler for Leaving {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send("bye")?;
+        if msg.as_text()? == "reason" {
+            self.out.close_with_reason(CloseCode::Away, "going away")
+        } else {
+            self.out.close(CloseCode::Away)
+        }
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.closed.send((code, reason.to_owned())).unwrap();
+    }
+}
+
+#[test]
+fn local_close_reports_requested_code() {
+    let (tx, rx) = channel();
+
+    let ws = ws::WebSocket::new(move |out| {
+        Leaving { out: out, closed: tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    for &(request, reason) in &[("plain", ""), ("reason", "going away")] {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+
+        // output sent before the close still goes out, then the connection is closed
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"bye");
+
+        let (code, got) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(code, CloseCode::Away);
+        let away: u16 = code.into();
+        assert_eq!(away, 1001);
+        assert_eq!(got, reason);
+        assert!(rx.try_recv().is_err());
+    }
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+struct Conditional {
+    out: Sender,
+    senders: ChannelSender<Sender>,
+    replies: ChannelSender<(Receiver<bool>, Receiver<bool>)>,
+}
+
+impl ws::Handler for Conditional {
+    fn on_open(&mut self) -> Result<()> {
+        self.senders.send(self.out.clone()).unwrap();
+        Ok(())
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        let open = self.out.try_send_if_open("open")?;
+        self.out.close(CloseCode::Normal)?;
+        let closing = self.out.try_send_if_open("closing")?;
+        self.replies.send((open, closing)).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn send_if_open() {
+    let (senders_tx, senders_rx) = channel();
+    let (replies_tx, replies_rx) = channel();
+
+    let ws = ws::WebSocket::new(move |out| {
+        Conditional { out: out, senders: senders_tx.clone(), replies: replies_tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    let out = senders_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+
+    client.write_all(b"go").unwrap();
+    let (open, closing) = replies_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert_eq!(Ok(true), open.recv_timeout(Duration::from_secs(2)));
+    assert_eq!(Ok(false), closing.recv_timeout(Duration::from_secs(2)));
+
+    // only the message sent while the connection was open arrives
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).unwrap();
+    assert_eq!(b"open".to_vec(), received);
+
+    // and nothing is sent once the connection is gone
+    while handle.diagnostics().unwrap().recv().unwrap().connections > 0 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let reply = out.try_send_if_open("gone").unwrap();
+    assert_eq!(Ok(false), reply.recv_timeout(Duration::from_secs(2)));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
@@ -0,0 +1,287 @@
+extern crate ws;
+
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Sender, Result};
+
+struct Client {
+    events: ChannelSender<&'static str>,
+}
+
+impl ws::Handler for Client {
+    fn on_connecting(&mut self, _: SocketAddr) {
+        self.events.send("connecting").unwrap();
+    }
+
+    fn on_open(&mut self) -> Result<()> {
+        self.events.send("open").unwrap();
+        Ok(())
+    }
+
+    fn on_error(&mut self, _: ws::Error) {
+        self.events.send("error").unwrap();
+    }
+}
+
+struct Server;
+
+impl ws::Handler for Server {}
+
+enum Either {
+    Client(Client),
+    Server(Server),
+}
+
+impl ws::Handler for Either {
+    fn on_connecting(&mut self, addr: SocketAddr) {
+        if let Either::Client(ref mut client) = *self {
+            client.on_connecting(addr)
+        }
+    }
+
+    fn on_open(&mut self) -> Result<()> {
+        match *self {
+            Either::Client(ref mut client) => client.on_open(),
+            Either::Server(ref mut server) => server.on_open(),
+        }
+    }
+
+    fn on_error(&mut self, err: ws::Error) {
+        match *self {
+            Either::Client(ref mut client) => client.on_error(err),
+            Either::Server(ref mut server) => server.on_error(err),
+        }
+    }
+}
+
+struct Factory {
+    events: ChannelSender<&'static str>,
+}
+
+impl ws::Factory for Factory {
+    type Handler = Either;
+
+    fn connection_made(&mut self, _: Sender) -> Either {
+        Either::Server(Server)
+    }
+
+    fn client_connected(&mut self, _: Sender) -> Either {
+        Either::Client(Client { events: self.events.clone() })
+    }
+}
+
+#[test]
+fn on_connecting_before_on_open() {
+    let (tx, rx) = channel();
+
+    let ws = ws::WebSocket::new(Factory { events: tx }).unwrap();
+    let mut ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    ws.connect(addr.to_string()).unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("connecting"));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("open"));
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+#[test]
+fn connect_to_resolved_addr() {
+    let (tx, rx) = channel();
+
+    let ws = ws::WebSocket::new(Factory { events: tx }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+    handle.connect_addr(addr).unwrap();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("connecting"));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("open"));
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+#[test]
+fn refused_connect_is_not_opened() {
+    let (tx, rx) = channel();
+
+    // nothing listens on the address once the listener is dropped
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    // the listener keeps the event loop running after the connection is dropped
+    let ws = ws::WebSocket::new(Factory { events: tx }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let handle = ws.broadcaster();
+    handle.connect_addr(addr).unwrap();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("connecting"));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("error"));
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+struct BadUrl {
+    out: Sender,
+    errors: ChannelSender<bool>,
+}
+
+impl ws::Handler for BadUrl {
+    fn on_open(&mut self) -> Result<()> {
+        self.out.connect("ws://[::1".into())
+    }
+
+    // the crate implements the older `cause` rather than `source`
+    #[allow(deprecated)]
+    fn on_error(&mut self, err: ws::Error) {
+        use std::error::Error;
+        let url = match err.kind {
+            ws::ErrorKind::Url(_) => err.cause().is_some(),
+            _ => false,
+        };
+        self.errors.send(url).unwrap();
+    }
+}
+
+#[test]
+fn malformed_url_is_reported() {
+    let (tx, rx) = channel();
+
+    let ws = ws::WebSocket::new(move |out| {
+        BadUrl { out: out, errors: tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let client = std::net::TcpStream::connect(addr).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+    drop(client);
+}
+
+struct Role {
+    out: Sender,
+    roles: ChannelSender<&'static str>,
+}
+
+impl ws::Handler for Role {
+    fn on_open(&mut self) -> Result<()> {
+        assert_eq!(self.out.is_server(), !self.out.is_client());
+        let role = if self.out.is_client() { "client" } else { "server" };
+        self.roles.send(role).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn sender_knows_its_role() {
+    let (tx, rx) = channel();
+
+    let ws = ws::WebSocket::new(move |out| {
+        Role { out: out, roles: tx.clone() }
+    }).unwrap();
+    let mut ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    ws.connect(addr.to_string()).unwrap();
+    let handle = ws.broadcaster();
+    assert!(!handle.is_client());
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut roles = vec![
+        rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+    ];
+    roles.sort();
+    assert_eq!(roles, vec!["client", "server"]);
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+struct Outgoing {
+    lost: ChannelSender<()>,
+}
+
+impl ws::Factory for Outgoing {
+    type Handler = Server;
+
+    fn connection_made(&mut self, _: Sender) -> Server {
+        Server
+    }
+
+    fn connection_lost(&mut self, _: Server) {
+        self.lost.send(()).unwrap();
+    }
+}
+
+#[test]
+fn outgoing_connections_are_limited() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (tx, rx) = channel();
+    let ws = ws::Builder::new().with_settings(ws::Settings {
+        max_outgoing_connections: 2,
+        ..ws::Settings::default()
+    }).build(Outgoing { lost: tx }).unwrap();
+    let handle = ws.broadcaster();
+
+    handle.connect_addr(addr).unwrap();
+    handle.connect_addr(addr).unwrap();
+    match handle.connect_addr(addr) {
+        Err(ws::Error { kind: ws::ErrorKind::Capacity, .. }) => (),
+        res => panic!("Expected a Capacity error, got {:?}", res),
+    }
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    // the limit still applies once the connections are established
+    let (first, _) = listener.accept().unwrap();
+    let (_second, _) = listener.accept().unwrap();
+    match handle.connect(addr.to_string()) {
+        Err(ws::Error { kind: ws::ErrorKind::Capacity, .. }) => (),
+        res => panic!("Expected a Capacity error, got {:?}", res),
+    }
+
+    // and frees up when one of them is dropped
+    drop(first);
+    rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    handle.connect_addr(addr).unwrap();
+    let _third = listener.accept().unwrap();
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
@@ -0,0 +1,91 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::time::Duration;
+
+use ws::{CloseCode, Message, Result, Sender};
+
+struct Waiting {
+    out: Sender,
+    stall_write: bool,
+    closed: ChannelSender<(CloseCode, String)>,
+}
+
+impl ws::Handler for Waiting {
+    fn on_open(&mut self) -> Result<()> {
+        if self.stall_write {
+            // far more than the socket buffers can take while the client doesn't read
+            self.out.send(vec![0u8; 32 << 20])?;
+            self.out.set_write_timeout(Duration::from_millis(300))
+        } else {
+            self.out.set_read_timeout(Duration::from_millis(300))
+        }
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.closed.send((code, reason.to_owned())).unwrap();
+    }
+}
+
+#[test]
+fn stalled_read_times_out() {
+    let (tx, rx) = channel();
+    let ws = ws::WebSocket::new(move |out| {
+        Waiting { out: out, stall_write: false, closed: tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    // answering before the deadline keeps the connection open
+    let mut prompt = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    prompt.write_all(b"here").unwrap();
+    let mut buf = [0u8; 4];
+    prompt.read_exact(&mut buf).unwrap();
+
+    let mut silent = TcpStream::connect(addr).unwrap();
+    let (code, reason) = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert_eq!(code, CloseCode::Away);
+    assert_eq!(reason, "Read timed out.");
+    assert_eq!(silent.read(&mut buf).unwrap(), 0);
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn stalled_write_times_out() {
+    let (tx, rx) = channel();
+    let ws = ws::WebSocket::new(move |out| {
+        Waiting { out: out, stall_write: true, closed: tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let client = TcpStream::connect(addr).unwrap();
+    let (code, reason) = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert_eq!(code, CloseCode::Away);
+    assert_eq!(reason, "Write timed out.");
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+    drop(client);
+}
@@ -0,0 +1,196 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Receiver, Sender as ChannelSender};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Diagnostics, Message, Result, Sender};
+use ws::util::Token;
+
+const IDLE: Token = Token(1);
+
+struct Reporter {
+    out: Sender,
+    opened: ChannelSender<()>,
+    reports: ChannelSender<Receiver<Diagnostics>>,
+}
+
+impl ws::Handler for Reporter {
+    fn on_open(&mut self) -> Result<()> {
+        self.opened.send(()).unwrap();
+        self.out.timeout(10_000, IDLE)
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        // both commands are queued before the loop gets to write anything
+        self.out.send(vec![0u8; 1000])?;
+        self.reports.send(self.out.diagnostics()?).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn diagnostics_snapshot() {
+    let (opened_tx, opened_rx) = channel();
+    let (reports_tx, reports_rx) = channel();
+
+    let ws = ws::WebSocket::new(move |out| {
+        Reporter { out: out, opened: opened_tx.clone(), reports: reports_tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut clients: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    for _ in 0..3 {
+        opened_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    }
+
+    clients[0].write_all(b"report").unwrap();
+    let report = reports_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    let diagnostics = report.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert_eq!(3, diagnostics.connections);
+    assert_eq!(3, diagnostics.open);
+    assert_eq!(0, diagnostics.connecting);
+    assert_eq!(0, diagnostics.closing);
+    assert_eq!(1000, diagnostics.queued_bytes);
+    assert_eq!(3, diagnostics.pending_timeouts);
+
+    let mut buf = [0u8; 1000];
+    clients[0].read_exact(&mut buf).unwrap();
+
+    let diagnostics = handle.diagnostics().unwrap().recv_timeout(Duration::from_secs(2)).unwrap();
+    assert_eq!(3, diagnostics.connections);
+    assert_eq!(0, diagnostics.queued_bytes);
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+const LARGE: usize = 16 << 20;
+
+struct Stalled {
+    out: Sender,
+    senders: ChannelSender<Sender>,
+    reports: ChannelSender<Receiver<Result<usize>>>,
+}
+
+impl ws::Handler for Stalled {
+    fn on_open(&mut self) -> Result<()> {
+        self.senders.send(self.out.clone()).unwrap();
+        Ok(())
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.out.send(vec![7u8; LARGE])?;
+        self.reports.send(self.out.queued_bytes()?).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn queued_bytes_behind_stalled_write() {
+    let (senders_tx, senders_rx) = channel();
+    let (reports_tx, reports_rx) = channel();
+
+    let ws = ws::WebSocket::new(move |out| {
+        Stalled { out: out, senders: senders_tx.clone(), reports: reports_tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    let out = senders_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+
+    // nothing has been written when the count is taken
+    client.write_all(b"go").unwrap();
+    let report = reports_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert_eq!(LARGE, report.recv_timeout(Duration::from_secs(2)).unwrap().unwrap());
+
+    // the client isn't reading, so most of the message stays buffered
+    thread::sleep(Duration::from_millis(200));
+    let queued = out.queued_bytes().unwrap().recv_timeout(Duration::from_secs(2)).unwrap().unwrap();
+    assert!(queued > 0 && queued <= LARGE);
+    let total = handle.queued_bytes().unwrap().recv_timeout(Duration::from_secs(2)).unwrap().unwrap();
+    assert_eq!(queued, total);
+
+    let mut buf = vec![0u8; LARGE];
+    client.read_exact(&mut buf).unwrap();
+    let queued = out.queued_bytes().unwrap().recv_timeout(Duration::from_secs(2)).unwrap().unwrap();
+    assert_eq!(0, queued);
+
+    // once the connection is gone there is nothing to count
+    drop(client);
+    while handle.diagnostics().unwrap().recv().unwrap().connections > 0 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    match out.queued_bytes().unwrap().recv_timeout(Duration::from_secs(2)).unwrap() {
+        Err(ws::Error { kind: ws::ErrorKind::NotFound, .. }) => (),
+        res => panic!("Expected a NotFound error, got {:?}", res),
+    }
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn commands_drained_in_bounded_iterations() {
+    use ws::{Builder, Settings};
+
+    for &(per_poll, max_iterations) in &[(100, 11), (1000, 2)] {
+        let ws = Builder::new().with_settings(Settings {
+            max_commands_per_poll: per_poll,
+            queue_size: 20,
+            ..Settings::default()
+        }).build(|out: Sender| {
+            move |msg| out.send(msg)
+        }).unwrap();
+        let ws = ws.bind("127.0.0.1:0").unwrap();
+        let handle = ws.broadcaster();
+
+        // everything is queued before the event loop starts, so the bursts are handled at once
+        for _ in 0..999 {
+            handle.broadcast("burst").unwrap();
+        }
+        let report = handle.diagnostics().unwrap();
+
+        let t = thread::spawn(move || {
+            ws.run().unwrap();
+        });
+
+        let diagnostics = report.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert!(diagnostics.iterations <= max_iterations,
+                "{} commands per poll took {} iterations", per_poll, diagnostics.iterations);
+
+        handle.shutdown().unwrap();
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn zero_commands_per_poll_rejected() {
+    use ws::{Builder, Settings};
+
+    let res = Builder::new().with_settings(Settings {
+        max_commands_per_poll: 0,
+        ..Settings::default()
+    }).build(|out: Sender| {
+        move |msg| out.send(msg)
+    });
+    match res {
+        Err(ws::Error { kind: ws::ErrorKind::Capacity, .. }) => (),
+        Err(err) => panic!("Expected a Capacity error, got {:?}", err),
+        Ok(_) => panic!("Built a WebSocket that never handles queued commands"),
+    }
+}
@@ -0,0 +1,70 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use ws::{Builder, Settings, Sender, Message, Result};
+
+struct Echo {
+    out: Sender,
+}
+
+impl ws::Handler for Echo {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+}
+
+struct Flaky {
+    made: usize,
+}
+
+impl ws::Factory for Flaky {
+    type Handler = Echo;
+
+    fn connection_made(&mut self, out: Sender) -> Echo {
+        self.made += 1;
+        if self.made == 2 {
+            panic!("unable to set up connection state");
+        }
+        Echo { out: out }
+    }
+}
+
+fn echo(client: &mut TcpStream) {
+    client.write_all(b"ping").unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[test]
+fn factory_panic_rejects_one_connection() {
+    let ws = Builder::new().with_settings(Settings {
+        catch_handler_panics: true,
+        ..Settings::default()
+    }).build(Flaky { made: 0 }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut first = TcpStream::connect(addr).unwrap();
+    echo(&mut first);
+
+    // the factory panics for this one, so the server closes it straight away
+    let mut rejected = TcpStream::connect(addr).unwrap();
+    let mut buf = Vec::new();
+    assert_eq!(rejected.read_to_end(&mut buf).unwrap_or(0), 0);
+
+    let mut third = TcpStream::connect(addr).unwrap();
+    echo(&mut third);
+    echo(&mut first);
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
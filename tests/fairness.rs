@@ -0,0 +1,61 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{Builder, Message, Sender, Settings};
+
+#[test]
+fn flooding_peer_does_not_starve_others() {
+    let ws = Builder::new().with_settings(Settings {
+        max_read_bytes_per_poll: Some(16 << 10),
+        ..Settings::default()
+    }).build(|out: Sender| {
+        move |msg: Message| {
+            if msg.as_text()? == "ping" {
+                out.send("pong")
+            } else {
+                Ok(())
+            }
+        }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut slow = TcpStream::connect(addr).unwrap();
+    let flooding = Arc::new(AtomicBool::new(true));
+    let flood = {
+        let flooding = flooding.clone();
+        let mut flooder = TcpStream::connect(addr).unwrap();
+        thread::spawn(move || {
+            let data = vec![b'x'; 64 << 10];
+            while flooding.load(Ordering::Relaxed) {
+                if flooder.write_all(&data).is_err() {
+                    break
+                }
+            }
+        })
+    };
+
+    thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    slow.write_all(b"ping").unwrap();
+    let mut buf = [0u8; 4];
+    slow.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"pong");
+    assert!(start.elapsed() < Duration::from_secs(1), "took {:?}", start.elapsed());
+
+    flooding.store(false, Ordering::Relaxed);
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+    flood.join().unwrap();
+}
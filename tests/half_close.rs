@@ -0,0 +1,58 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Sender, Message, Result, CloseCode};
+
+const RESPONSE: usize = 32 << 20;
+
+struct Large {
+    out: Sender,
+    closed: ChannelSender<CloseCode>,
+}
+
+impl ws::Handler for Large {
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        // more than the socket buffers can hold, so most of it is still buffered at the half-close
+        self.out.send(vec![7u8; RESPONSE])
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.closed.send(code).unwrap();
+    }
+}
+
+#[test]
+fn flush_response_after_half_close() {
+    let (tx, rx) = channel();
+
+    let ws = ws::WebSocket::new(move |out| {
+        Large { out: out, closed: tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"go").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    client.shutdown(Shutdown::Write).unwrap();
+
+    let mut buf = Vec::new();
+    client.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf.len(), RESPONSE);
+    assert!(buf.iter().all(|&b| b == 7));
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), CloseCode::Abnormal);
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
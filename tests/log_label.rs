@@ -0,0 +1,179 @@
+extern crate ws;
+extern crate log;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{LogLevelFilter, LogMetadata, LogRecord};
+use ws::{Builder, DuplicateLabels, Settings, Sender, Message, Result};
+
+struct Capture {
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl log::Log for Capture {
+    fn enabled(&self, _: &LogMetadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &LogRecord) {
+        self.lines.lock().unwrap().push(format!("{}", record.args()));
+    }
+}
+
+struct Labeled {
+    out: Sender,
+}
+
+impl ws::Handler for Labeled {
+    fn on_open(&mut self) -> Result<()> {
+        self.out.set_log_label("user-42".into())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+}
+
+#[test]
+fn label_appears_in_log_lines() {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let captured = lines.clone();
+    log::set_logger(move |max| {
+        max.set(LogLevelFilter::Trace);
+        Box::new(Capture { lines: captured })
+    }).unwrap();
+
+    let ws = ws::WebSocket::new(|out| Labeled { out: out }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"ping").unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).unwrap();
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+
+    let peer = client.local_addr().unwrap();
+    let labeled = format!("{} [user-42]", peer);
+    let lines = lines.lock().unwrap();
+    assert!(lines.iter().any(|line| line.contains(&labeled)), "no log line mentions {}", labeled);
+}
+
+// Takes the first message as the connection's label and echoes the rest.
+struct Named {
+    out: Sender,
+    named: bool,
+}
+
+impl ws::Handler for Named {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        if self.named {
+            return self.out.send(msg)
+        }
+        self.named = true;
+        self.out.set_log_label(msg.into_text()?)?;
+        self.out.send("ok")
+    }
+}
+
+fn read(client: &mut TcpStream, len: usize) -> String {
+    let mut buf = vec![0u8; len];
+    client.read_exact(&mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+fn named(addr: ::std::net::SocketAddr, name: &str) -> TcpStream {
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(name.as_bytes()).unwrap();
+    assert_eq!(read(&mut client, 2), "ok");
+    client
+}
+
+#[test]
+fn send_to_label() {
+    let ws = ws::WebSocket::new(|out| Named { out: out, named: false }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut alice = named(addr, "alice");
+    let mut bob = named(addr, "bob");
+
+    assert_eq!(handle.send_to_label("bob", "to bob").unwrap().recv().unwrap().unwrap(), 1);
+    assert_eq!(handle.send_to_label("alice", "to alice").unwrap().recv().unwrap().unwrap(), 1);
+    assert_eq!(read(&mut bob, 6), "to bob");
+    assert_eq!(read(&mut alice, 8), "to alice");
+
+    match handle.send_to_label("carol", "to carol").unwrap().recv().unwrap() {
+        Err(ws::Error { kind: ws::ErrorKind::NotFound, .. }) => (),
+        res => panic!("Expected a NotFound error, got {:?}", res),
+    }
+
+    // the label goes away with the connection
+    drop(bob);
+    while handle.diagnostics().unwrap().recv().unwrap().connections > 1 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    match handle.send_to_label("bob", "to bob").unwrap().recv().unwrap() {
+        Err(ws::Error { kind: ws::ErrorKind::NotFound, .. }) => (),
+        res => panic!("Expected a NotFound error, got {:?}", res),
+    }
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn send_to_duplicate_label() {
+    for &policy in &[DuplicateLabels::DeliverToAll, DuplicateLabels::Reject] {
+        let ws = Builder::new().with_settings(Settings {
+            duplicate_labels: policy,
+            ..Settings::default()
+        }).build(|out| Named { out: out, named: false }).unwrap();
+        let ws = ws.bind("127.0.0.1:0").unwrap();
+        let addr = ws.local_addr().unwrap();
+        let handle = ws.broadcaster();
+
+        let t = thread::spawn(move || {
+            ws.run().unwrap();
+        });
+
+        let mut first = named(addr, "twin");
+        let mut second = named(addr, "twin");
+
+        let res = handle.send_to_label("twin", "hi").unwrap().recv().unwrap();
+        if policy == DuplicateLabels::DeliverToAll {
+            assert_eq!(res.unwrap(), 2);
+            assert_eq!(read(&mut first, 2), "hi");
+            assert_eq!(read(&mut second, 2), "hi");
+        } else {
+            match res {
+                Err(ws::Error { kind: ws::ErrorKind::Internal, .. }) => (),
+                res => panic!("Expected an Internal error, got {:?}", res),
+            }
+            // neither connection got the message
+            first.write_all(b"ping").unwrap();
+            assert_eq!(read(&mut first, 4), "ping");
+            second.write_all(b"ping").unwrap();
+            assert_eq!(read(&mut second, 4), "ping");
+        }
+
+        handle.shutdown().unwrap();
+        t.join().unwrap();
+    }
+}
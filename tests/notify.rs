@@ -0,0 +1,60 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Sender, Message, Result};
+use ws::util::Token;
+
+const REQUEST: Token = Token(7);
+const LARGE: usize = 32 << 20;
+
+struct Rpc {
+    out: Sender,
+    sent: ChannelSender<Token>,
+}
+
+impl ws::Handler for Rpc {
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        // more than the socket buffers can hold while the peer isn't reading
+        self.out.send_and_notify(vec![1u8; LARGE], REQUEST)
+    }
+
+    fn on_sent(&mut self, event: Token) -> Result<()> {
+        self.sent.send(event).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn on_sent_after_flush() {
+    let (tx, rx) = channel();
+
+    let ws = ws::WebSocket::new(move |out| {
+        Rpc { out: out, sent: tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"go").unwrap();
+
+    // the message can't be flushed until the client reads it
+    assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+
+    let mut buf = vec![0u8; LARGE];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), REQUEST);
+    assert!(rx.try_recv().is_err());
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
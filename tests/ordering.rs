@@ -0,0 +1,146 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use ws::{Sender, Message, Result};
+
+struct Interleave {
+    out: Sender,
+}
+
+impl ws::Handler for Interleave {
+    fn on_open(&mut self) -> Result<()> {
+        self.out.send("hi")
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.out.broadcast("b1")?;
+        self.out.send("s1")?;
+        self.out.broadcast("b2")?;
+        self.out.send("s2")?;
+        self.out.broadcast("b3")
+    }
+}
+
+fn read(client: &mut TcpStream, len: usize) -> String {
+    let mut buf = vec![0u8; len];
+    client.read_exact(&mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+#[test]
+fn broadcast_and_send_keep_queue_order() {
+    let ws = ws::WebSocket::new(|out| Interleave { out: out }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut bystander = TcpStream::connect(addr).unwrap();
+    assert_eq!(read(&mut bystander, 2), "hi");
+    let mut sender = TcpStream::connect(addr).unwrap();
+    assert_eq!(read(&mut sender, 2), "hi");
+
+    sender.write_all(b"go").unwrap();
+    assert_eq!(read(&mut sender, 10), "b1s1b2s2b3");
+    assert_eq!(read(&mut bystander, 6), "b1b2b3");
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+const LARGE: usize = 8 << 20;
+
+struct LargeBroadcast {
+    out: Sender,
+}
+
+impl ws::Handler for LargeBroadcast {
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.out.send("head")?;
+        // too large to go out in one write, so it stays partly buffered
+        self.out.broadcast(vec![7u8; LARGE])?;
+        self.out.send("tail")
+    }
+}
+
+#[test]
+fn large_broadcast_stays_in_order() {
+    let ws = ws::WebSocket::new(|out| LargeBroadcast { out: out }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"go").unwrap();
+
+    let mut buf = vec![0u8; LARGE + 8];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..4], b"head");
+    assert!(buf[4..LARGE + 4].iter().all(|&b| b == 7));
+    assert_eq!(&buf[LARGE + 4..], b"tail");
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn batched_broadcast_keeps_order() {
+    use ws::{Builder, Settings};
+
+    let ws = Builder::new().with_settings(Settings {
+        broadcast_batch_size: 2,
+        ..Settings::default()
+    }).build(|out| Interleave { out: out }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut bystanders = Vec::new();
+    for _ in 0..10 {
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert_eq!(read(&mut client, 2), "hi");
+        bystanders.push(client);
+    }
+    let mut sender = TcpStream::connect(addr).unwrap();
+    assert_eq!(read(&mut sender, 2), "hi");
+
+    // the broadcasts take several iterations to reach everyone, but the direct sends in
+    // between never overtake them
+    sender.write_all(b"go").unwrap();
+    assert_eq!(read(&mut sender, 10), "b1s1b2s2b3");
+    for client in bystanders.iter_mut() {
+        assert_eq!(read(client, 6), "b1b2b3");
+    }
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn zero_broadcast_batch_size_rejected() {
+    use ws::{Builder, Settings};
+
+    let res = Builder::new().with_settings(Settings {
+        broadcast_batch_size: 0,
+        ..Settings::default()
+    }).build(|out| Interleave { out: out });
+    match res {
+        Err(ws::Error { kind: ws::ErrorKind::Capacity, .. }) => (),
+        Err(err) => panic!("Expected a Capacity error, got {:?}", err),
+        Ok(_) => panic!("Built a WebSocket that never delivers broadcasts"),
+    }
+}
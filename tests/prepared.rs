@@ -0,0 +1,56 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use ws::{Sender, Message, PreparedMessage, Result};
+
+struct Snapshot {
+    out: Sender,
+    snapshot: PreparedMessage,
+}
+
+impl ws::Handler for Snapshot {
+    fn on_open(&mut self) -> Result<()> {
+        self.out.send_prepared(&self.snapshot)
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.out.broadcast_prepared(&self.snapshot)?;
+        self.out.send("!")
+    }
+}
+
+fn read(client: &mut TcpStream, len: usize) -> String {
+    let mut buf = vec![0u8; len];
+    client.read_exact(&mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+#[test]
+fn prepared_messages_are_sent_and_broadcast() {
+    let snapshot = Message::text("snap").prepare();
+    let ws = ws::WebSocket::new(move |out| {
+        Snapshot { out: out, snapshot: snapshot.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut first = TcpStream::connect(addr).unwrap();
+    assert_eq!(read(&mut first, 4), "snap");
+    let mut second = TcpStream::connect(addr).unwrap();
+    assert_eq!(read(&mut second, 4), "snap");
+
+    first.write_all(b"go").unwrap();
+    assert_eq!(read(&mut first, 5), "snap!");
+    assert_eq!(read(&mut second, 4), "snap");
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
@@ -0,0 +1,43 @@
+extern crate ws;
+extern crate log;
+
+use std::sync::{Arc, Mutex};
+
+use log::{LogLevel, LogLevelFilter, LogMetadata, LogRecord};
+use ws::{Error, ErrorKind, Handler};
+
+struct Recorder {
+    levels: Arc<Mutex<Vec<LogLevel>>>,
+}
+
+impl log::Log for Recorder {
+    fn enabled(&self, _: &LogMetadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if record.target().starts_with("ws") {
+            self.levels.lock().unwrap().push(record.level());
+        }
+    }
+}
+
+struct Quiet;
+impl Handler for Quiet {}
+
+#[test]
+fn expected_errors_logged_at_debug() {
+    let levels = Arc::new(Mutex::new(Vec::new()));
+    let recorded = levels.clone();
+    log::set_logger(move |max| {
+        max.set(LogLevelFilter::Debug);
+        Box::new(Recorder { levels: recorded })
+    }).unwrap();
+
+    let mut handler = Quiet;
+    handler.on_error(Error::new(ErrorKind::Protocol, "malformed input"));
+    assert_eq!(levels.lock().unwrap().drain(..).collect::<Vec<_>>(), vec![LogLevel::Debug]);
+
+    handler.on_error(Error::new(ErrorKind::Internal, "bug"));
+    assert_eq!(levels.lock().unwrap().drain(..).collect::<Vec<_>>(), vec![LogLevel::Error]);
+}
@@ -38,3 +38,240 @@ fn shutdown_before_connections() {
     assert!(t.join().is_ok());
 
 }
+
+#[test]
+fn repeated_shutdown_tears_down_once() {
+    use std::net::TcpStream;
+    use std::sync::mpsc::Sender;
+
+    struct Counted {
+        out: ws::Sender,
+        events: Sender<&'static str>,
+    }
+
+    impl ws::Handler for Counted {
+        fn on_open(&mut self) -> ws::Result<()> {
+            self.events.send("open").unwrap();
+            // both are queued before the event loop gets to the first one
+            self.out.shutdown()?;
+            self.out.shutdown()
+        }
+
+        fn on_shutdown(&mut self) {
+            self.events.send("shutdown").unwrap();
+        }
+
+        fn on_close(&mut self, _: ws::CloseCode, _: &str) {
+            self.events.send("close").unwrap();
+        }
+    }
+
+    struct Factory {
+        events: Sender<&'static str>,
+    }
+
+    impl ws::Factory for Factory {
+        type Handler = Counted;
+
+        fn connection_made(&mut self, out: ws::Sender) -> Counted {
+            Counted { out: out, events: self.events.clone() }
+        }
+
+        fn on_shutdown(&mut self) {
+            self.events.send("factory shutdown").unwrap();
+        }
+    }
+
+    let (tx, rx) = channel();
+    let ws = ws::WebSocket::new(Factory { events: tx }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let client = TcpStream::connect(addr).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok("open"));
+    assert!(t.join().is_ok());
+
+    let events: Vec<_> = rx.try_iter().collect();
+    assert_eq!(events, vec!["shutdown", "close", "factory shutdown"]);
+    drop(client);
+}
+
+#[test]
+fn shutdown_in_token_order() {
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::sync::mpsc::Sender;
+
+    struct Ordered {
+        out: ws::Sender,
+        events: Sender<(&'static str, usize)>,
+    }
+
+    impl ws::Handler for Ordered {
+        fn on_open(&mut self) -> ws::Result<()> {
+            self.events.send(("open", self.out.token().0)).unwrap();
+            Ok(())
+        }
+
+        fn on_shutdown(&mut self) {
+            self.events.send(("shutdown", self.out.token().0)).unwrap();
+        }
+
+        fn on_close(&mut self, _: ws::CloseCode, _: &str) {
+            self.events.send(("close", self.out.token().0)).unwrap();
+        }
+    }
+
+    let (tx, rx) = channel();
+    let ws = ws::WebSocket::new(move |out| Ordered { out: out, events: tx.clone() }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut clients = Vec::new();
+    let mut tokens = Vec::new();
+    for _ in 0..4 {
+        clients.push(TcpStream::connect(addr).unwrap());
+        let (event, token) = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!("open", event);
+        tokens.push(token);
+    }
+
+    // free up a token in the middle, so the next connection reuses it
+    let mut dropped = clients.remove(1);
+    dropped.shutdown(::std::net::Shutdown::Write).unwrap();
+    let mut buf = Vec::new();
+    dropped.read_to_end(&mut buf).unwrap();
+    assert_eq!(("close", tokens[1]), rx.recv_timeout(Duration::from_secs(2)).unwrap());
+    clients.push(TcpStream::connect(addr).unwrap());
+    assert_eq!(("open", tokens[1]), rx.recv_timeout(Duration::from_secs(2)).unwrap());
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+
+    tokens.sort();
+    let mut expected = Vec::new();
+    for token in tokens {
+        expected.push(("shutdown", token));
+        expected.push(("close", token));
+    }
+    let events: Vec<_> = rx.try_iter().collect();
+    assert_eq!(expected, events);
+}
+
+#[test]
+fn stop_and_resume_accepting() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn echo(client: &mut TcpStream) {
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    let ws = ws::WebSocket::new(|out: ws::Sender| {
+        move |msg| out.send(msg)
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut open = TcpStream::connect(addr).unwrap();
+    echo(&mut open);
+
+    handle.stop_accepting().unwrap();
+    // queued behind the stop, so it is carried out by the time the reply arrives
+    handle.diagnostics().unwrap().recv_timeout(Duration::from_secs(2)).unwrap();
+
+    // the new connection isn't served, while the open one still is
+    let mut waiting = TcpStream::connect(addr).unwrap();
+    waiting.write_all(b"ping").unwrap();
+    waiting.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let mut buf = [0u8; 4];
+    assert!(waiting.read(&mut buf).is_err());
+    echo(&mut open);
+
+    handle.resume_accepting().unwrap();
+    waiting.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    waiting.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+#[test]
+fn loop_start_and_stop() {
+    use std::net::TcpStream;
+    use std::sync::mpsc::Sender;
+
+    struct Lifecycle {
+        events: Sender<&'static str>,
+    }
+
+    impl ws::Handler for Lifecycle {
+        fn on_open(&mut self) -> ws::Result<()> {
+            self.events.send("open").unwrap();
+            Ok(())
+        }
+
+        fn on_close(&mut self, _: ws::CloseCode, _: &str) {
+            self.events.send("close").unwrap();
+        }
+    }
+
+    struct Factory {
+        events: Sender<&'static str>,
+    }
+
+    impl ws::Factory for Factory {
+        type Handler = Lifecycle;
+
+        fn connection_made(&mut self, _: ws::Sender) -> Lifecycle {
+            Lifecycle { events: self.events.clone() }
+        }
+
+        fn on_loop_start(&mut self) {
+            self.events.send("loop start").unwrap();
+        }
+
+        fn on_loop_stop(&mut self) {
+            self.events.send("loop stop").unwrap();
+        }
+    }
+
+    let (tx, rx) = channel();
+    let ws = ws::WebSocket::new(Factory { events: tx }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok("loop start"));
+    let client = TcpStream::connect(addr).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok("open"));
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+
+    let events: Vec<_> = rx.try_iter().collect();
+    assert_eq!(events, vec!["close", "loop stop"]);
+    drop(client);
+}
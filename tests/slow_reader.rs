@@ -0,0 +1,65 @@
+extern crate ws;
+
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, Settings, Sender, Message, Result};
+
+struct Flood {
+    out: Sender,
+}
+
+impl ws::Handler for Flood {
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        for _ in 0..64 {
+            self.out.send(vec![0u8; 1 << 20])?;
+        }
+        Ok(())
+    }
+}
+
+struct FloodFactory {
+    lost: ChannelSender<()>,
+}
+
+impl ws::Factory for FloodFactory {
+    type Handler = Flood;
+
+    fn connection_made(&mut self, out: Sender) -> Flood {
+        Flood { out: out }
+    }
+
+    fn connection_lost(&mut self, _: Flood) {
+        self.lost.send(()).unwrap();
+    }
+}
+
+#[test]
+fn drop_peer_that_never_reads() {
+    let (tx, rx) = channel();
+
+    let ws = Builder::new().with_settings(Settings {
+        max_out_buffer_bytes: Some(4 << 20),
+        ..Settings::default()
+    }).build(FloodFactory { lost: tx }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    // Trigger the flood and then never read a single byte of it.
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"go").unwrap();
+
+    assert!(rx.recv_timeout(Duration::from_secs(10)).is_ok());
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+    drop(client);
+}
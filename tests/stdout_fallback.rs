@@ -0,0 +1,65 @@
+extern crate ws;
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+
+use ws::{Builder, Settings, Message, Result, Error, ErrorKind};
+
+// The test harness captures what the event loop prints, so the connection is run by a child
+// process that prints straight to its own stdout.
+const CHILD: &'static str = "WS_STDOUT_FALLBACK_CHILD";
+
+struct Failing;
+
+impl ws::Handler for Failing {
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        Err(Error::new(ErrorKind::Capacity, "failing on purpose"))
+    }
+}
+
+#[test]
+fn failing_connection() {
+    let fallback = match env::var(CHILD) {
+        Ok(value) => value == "on",
+        Err(_) => return,
+    };
+
+    let ws = Builder::new().with_settings(Settings {
+        error_stdout_fallback: fallback,
+        ..Settings::default()
+    }).build(|_| Failing).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"fail").unwrap();
+    let mut buf = Vec::new();
+    client.read_to_end(&mut buf).unwrap();
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+fn child_stdout(fallback: &str) -> String {
+    let output = Command::new(env::current_exe().unwrap())
+        .args(&["--exact", "failing_connection", "--nocapture", "--test-threads=1"])
+        .env(CHILD, fallback)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn stdout_fallback_can_be_disabled() {
+    assert!(child_stdout("on").contains("Encountered an error"));
+    assert!(!child_stdout("off").contains("Encountered an error"));
+}
@@ -0,0 +1,27 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+
+use ws::Builder;
+
+#[test]
+fn build_threaded_connect_and_shutdown() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (sender, handle) = Builder::new().build_threaded(|out: ws::Sender| {
+        move |msg| out.send(msg)
+    }).unwrap();
+    assert_eq!(handle.thread().name(), Some("ws-event-loop"));
+
+    sender.connect(addr.to_string()).unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+    peer.write_all(b"echo").unwrap();
+    let mut buf = [0u8; 4];
+    peer.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"echo");
+
+    sender.shutdown().unwrap();
+    handle.join().unwrap().unwrap();
+}
@@ -0,0 +1,227 @@
+extern crate ws;
+
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{Builder, Settings, Sender, Result, Error, ErrorKind};
+use ws::util::Token;
+
+const WAKE: Token = Token(1);
+
+struct Sleeper {
+    out: Sender,
+    fired: ChannelSender<Instant>,
+}
+
+impl ws::Handler for Sleeper {
+    fn on_open(&mut self) -> Result<()> {
+        self.out.timeout(100, WAKE)
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        assert_eq!(event, WAKE);
+        self.fired.send(Instant::now()).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn timeout_fires_without_other_events() {
+    let (tx, rx) = channel();
+
+    let ws = Builder::new().with_settings(Settings {
+        max_poll_timeout_ms: Some(50),
+        ..Settings::default()
+    }).build(move |out| {
+        Sleeper { out: out, fired: tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let start = Instant::now();
+    let client = TcpStream::connect(addr).unwrap();
+
+    let fired = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    let elapsed = fired.duration_since(start);
+    // the timer works in 100ms ticks, so there is no finer guarantee than this
+    assert!(elapsed < Duration::from_millis(1000), "fired too late: {:?}", elapsed);
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+    drop(client);
+}
+
+const DONE: Token = Token(2);
+
+struct Canceller {
+    out: Sender,
+    fired: ChannelSender<Token>,
+}
+
+impl ws::Handler for Canceller {
+    fn on_open(&mut self) -> Result<()> {
+        for i in 0..3 {
+            self.out.timeout(100 + i * 100, Token(10 + i as usize))?;
+        }
+        self.out.cancel_all_timeouts()?;
+        // scheduled after the cancellation, so this one still fires
+        self.out.timeout(500, DONE)
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        self.fired.send(event).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn cancel_all_timeouts() {
+    let (tx, rx) = channel();
+
+    let ws = ws::WebSocket::new(move |out| {
+        Canceller { out: out, fired: tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let client = TcpStream::connect(addr).unwrap();
+    assert_eq!(DONE, rx.recv_timeout(Duration::from_secs(2)).unwrap());
+    assert!(rx.try_recv().is_err());
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+    drop(client);
+}
+
+const PENDING: Token = Token(3);
+
+struct Forgetful {
+    out: Sender,
+    dropped: ChannelSender<Token>,
+}
+
+impl ws::Handler for Forgetful {
+    fn on_open(&mut self) -> Result<()> {
+        self.out.timeout(10_000, PENDING)?;
+        self.out.close(ws::CloseCode::Normal)
+    }
+
+    fn on_timeout_dropped(&mut self, event: Token) {
+        self.dropped.send(event).unwrap();
+    }
+}
+
+#[test]
+fn pending_timeout_dropped_on_close() {
+    let (tx, rx) = channel();
+
+    let ws = ws::WebSocket::new(move |out| {
+        Forgetful { out: out, dropped: tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let client = TcpStream::connect(addr).unwrap();
+    assert_eq!(PENDING, rx.recv_timeout(Duration::from_secs(2)).unwrap());
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+    drop(client);
+}
+
+const AGAIN: Token = Token(4);
+
+struct Overflowing {
+    out: Sender,
+    fired: usize,
+    events: ChannelSender<Option<Token>>,
+}
+
+impl ws::Handler for Overflowing {
+    fn on_open(&mut self) -> Result<()> {
+        // one more than the timer holds
+        for i in 0..5 {
+            self.out.timeout(50, Token(10 + i))?;
+        }
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        self.events.send(Some(event)).unwrap();
+        self.fired += 1;
+        if self.fired == 4 {
+            // there is room again once the timeouts have fired
+            self.out.timeout(50, AGAIN)?;
+        }
+        Ok(())
+    }
+
+    fn on_error(&mut self, err: Error) {
+        match err.kind {
+            ErrorKind::Timer(_) => self.events.send(None).unwrap(),
+            _ => panic!("Expected a Timer error, got {:?}", err),
+        }
+    }
+}
+
+#[test]
+fn timer_capacity() {
+    let (tx, rx) = channel();
+
+    let ws = Builder::new().with_settings(Settings {
+        timer_capacity: 4,
+        timer_tick_ms: 10,
+        ..Settings::default()
+    }).build(move |out| {
+        Overflowing { out: out, fired: 0, events: tx.clone() }
+    }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let client = TcpStream::connect(addr).unwrap();
+    let events: Vec<_> = (0..6).map(|_| rx.recv_timeout(Duration::from_secs(2)).unwrap()).collect();
+    assert_eq!(events.iter().filter(|event| event.is_none()).count(), 1);
+    assert_eq!(events.iter().filter(|event| event.is_some()).count(), 5);
+    assert_eq!(events[5], Some(AGAIN));
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+    drop(client);
+}
+
+#[test]
+fn zero_timer_tick_rejected() {
+    let res = Builder::new().with_settings(Settings {
+        timer_tick_ms: 0,
+        ..Settings::default()
+    }).build(|out: Sender| {
+        move |msg| out.send(msg)
+    });
+    match res {
+        Err(Error { kind: ErrorKind::Timer(_), .. }) => (),
+        Err(err) => panic!("Expected a Timer error, got {:?}", err),
+        Ok(_) => panic!("Built a WebSocket with a zero timer tick"),
+    }
+}
@@ -0,0 +1,58 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Error, ErrorKind, Result, CloseCode};
+
+struct Gatekeeper {
+    events: ChannelSender<String>,
+}
+
+impl ws::Handler for Gatekeeper {
+    fn on_open(&mut self) -> Result<()> {
+        Err(Error::new(ErrorKind::Internal, "invalid token"))
+    }
+
+    fn on_error(&mut self, err: Error) {
+        self.events.send(format!("error: {}", err)).unwrap();
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.events.send(format!("close: {:?}", code)).unwrap();
+    }
+}
+
+#[test]
+fn on_open_error_rejects_connection() {
+    let (tx, rx) = channel();
+
+    let ws = ws::WebSocket::new(move |_| Gatekeeper { events: tx.clone() }).unwrap();
+    let ws = ws.bind("127.0.0.1:0").unwrap();
+    let addr = ws.local_addr().unwrap();
+    let handle = ws.broadcaster();
+
+    let t = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = Vec::new();
+    // an orderly shutdown rather than a reset, and the event loop survives the internal error
+    assert_eq!(client.read_to_end(&mut buf).unwrap(), 0);
+
+    assert_eq!(rx.recv().unwrap(), "error: Internal Application Error: invalid token");
+    assert!(rx.recv().unwrap().starts_with("close"));
+
+    // the server keeps accepting
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"hello").unwrap();
+    assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap().starts_with("error"));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}